@@ -1,13 +1,19 @@
 //! ACPIテーブル解析用コード
 //!
-//! ここではLocal APIC idのリスト取得に必要なMADTと
+//! ここではLocal APIC idのリストとI/O APICの情報の取得に必要なMADTと
 //! ACPI PM Timerの取得に必要なFACPテーブルの解析のみを行っています。
 //! なお、チャックサムの確認は省略しています。
+//! テーブルのアドレスは物理アドレスで扱い、読み込む前にダイレクトマップ上にマップします。
 
 use super::acpi_pm_timer::AcpiPmTimer;
+use super::io_apic::IoApicManager;
+use super::local_apic::LocalApicNmiList;
+use super::memory::ReservedAreaList;
+use super::paging::phys_to_virt;
+use super::PAGE_MANAGER;
 
 #[repr(C, packed)]
-struct RSDP {
+struct Rsdp {
     signature: [u8; 8],
     checksum: u8,
     oem_id: [u8; 6],
@@ -20,7 +26,7 @@ struct RSDP {
 }
 
 #[repr(C, packed)]
-struct MADT {
+struct Madt {
     signature: [u8; 4],
     length: u32,
     revision: u8,
@@ -36,7 +42,7 @@ struct MADT {
 }
 
 #[repr(C, packed)]
-struct FADT {
+struct Fadt {
     signature: [u8; 4],
     length: u32,
     major_version: u8,
@@ -46,11 +52,16 @@ struct FADT {
     oem_revision: u32,
     creator_id: [u8; 4],
     creator_revision: [u8; 4],
-    ignore: [u8; 76 - 36],
+    firmware_ctrl: u32,
+    dsdt: u32,
+    ignore: [u8; 76 - 44],
     pm_tmr_block: u32,
     ignore2: [u8; 112 - 80],
     flags: u32,
-    ignore3: [u8; 276 - 116],
+    ignore3: [u8; 132 - 116],
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    ignore4: [u8; 276 - 148],
 }
 
 #[derive(Clone)]
@@ -60,8 +71,19 @@ pub struct ApicIdList {
     pointer: usize,
 }
 
+/// 全てのACPIテーブルが持つヘッダの大きさ
+const TABLE_HEADER_SIZE: usize = 36;
+
+/// 物理アドレスにあるACPIテーブル全体をマップし、仮想アドレスを返す
+fn map_table(address: usize) -> usize {
+    let header = unsafe { PAGE_MANAGER.ensure_direct_map(address, TABLE_HEADER_SIZE) }
+        .expect("Cannot map the ACPI table");
+    let length = (unsafe { *((header + 4) as *const u32) } as usize).max(TABLE_HEADER_SIZE);
+    unsafe { PAGE_MANAGER.ensure_direct_map(address, length) }.expect("Cannot map the ACPI table")
+}
+
 fn get_xsdt(rsdp_address: usize) -> Option<usize> {
-    let rsdp = unsafe { &*(rsdp_address as *const RSDP) };
+    let rsdp = unsafe { &*(phys_to_virt(rsdp_address) as *const Rsdp) };
     if rsdp.revision < 2 {
         None
     } else {
@@ -70,14 +92,20 @@ fn get_xsdt(rsdp_address: usize) -> Option<usize> {
 }
 
 fn get_rsdt(rsdp_address: usize) -> usize {
-    unsafe { (&*(rsdp_address as *const RSDP)).rsdt_address as usize }
+    unsafe { (&*(phys_to_virt(rsdp_address) as *const Rsdp)).rsdt_address as usize }
 }
 
 fn get_entry(address: usize, index: usize, is_xsdt: bool) -> Option<usize> {
     let pointer_size = if is_xsdt { 8 } else { 4 };
-    let length = { unsafe { *((address + 4) as *const u32) } } as usize;
+    let table = map_table(address);
+    let length = { unsafe { *((table + 4) as *const u32) } } as usize;
     if (length - 0x24) > index * pointer_size {
-        Some(unsafe { *((address + 0x24 + index * pointer_size) as *const u32) } as usize)
+        let entry_address = table + 0x24 + index * pointer_size;
+        Some(if is_xsdt {
+            unsafe { core::ptr::read_unaligned(entry_address as *const u64) as usize }
+        } else {
+            unsafe { *(entry_address as *const u32) as usize }
+        })
     } else {
         None
     }
@@ -91,9 +119,7 @@ fn get_madt(rsdp_address: usize) -> Option<usize> {
     };
     let mut index = 0;
     while let Some(entry_address) = get_entry(address, index, is_xsdt) {
-        if unsafe { *(entry_address as *const [u8; 4]) }
-            == ['A' as u8, 'P' as u8, 'I' as u8, 'C' as u8]
-        {
+        if unsafe { *(map_table(entry_address) as *const [u8; 4]) } == *b"APIC" {
             return Some(entry_address);
         }
         index += 1;
@@ -101,15 +127,54 @@ fn get_madt(rsdp_address: usize) -> Option<usize> {
     None
 }
 
-pub fn get_apic_id_list(rsdp_address: usize) -> Option<ApicIdList> {
-    let madt_address = if let Some(madt) = get_madt(rsdp_address) {
-        madt
+fn get_table_length(address: usize) -> usize {
+    unsafe { *((map_table(address) + 4) as *const u32) as usize }
+}
+
+/// RSDT/XSDTと、そこから参照されているテーブル(FADTが指すDSDT・FACSを含む)の領域を追加する
+pub fn add_acpi_table_areas(rsdp_address: usize, reserved_areas: &mut ReservedAreaList) {
+    const NAME: &str = "ACPI tables";
+    let (address, is_xsdt) = if let Some(xsdt_address) = get_xsdt(rsdp_address) {
+        (xsdt_address, true)
     } else {
-        return None;
+        (get_rsdt(rsdp_address), false)
     };
-    let madt = unsafe { &*(madt_address as *const MADT) };
-    let length = madt.length as usize - core::mem::size_of::<MADT>();
-    let base_address = madt_address + core::mem::size_of::<MADT>();
+    if address == 0 {
+        return;
+    }
+    reserved_areas.add(address, get_table_length(address), NAME);
+
+    let mut index = 0;
+    while let Some(entry_address) = get_entry(address, index, is_xsdt) {
+        reserved_areas.add(entry_address, get_table_length(entry_address), NAME);
+        let table = map_table(entry_address);
+        if unsafe { *(table as *const [u8; 4]) } == *b"FACP" {
+            let fadt = unsafe { &*(table as *const Fadt) };
+            /* リビジョン2以降はX_FIRMWARE_CTRL・X_DSDTが0でなければそちらを優先する */
+            let has_x_fields = fadt.major_version >= 2 && fadt.length as usize >= 148;
+            for (address, x_address) in [
+                (fadt.firmware_ctrl, fadt.x_firmware_ctrl),
+                (fadt.dsdt, fadt.x_dsdt),
+            ] {
+                let table_address = if has_x_fields && x_address != 0 {
+                    x_address as usize
+                } else {
+                    address as usize
+                };
+                if table_address != 0 {
+                    reserved_areas.add(table_address, get_table_length(table_address), NAME);
+                }
+            }
+        }
+        index += 1;
+    }
+}
+
+pub fn get_apic_id_list(rsdp_address: usize) -> Option<ApicIdList> {
+    let madt_address = map_table(get_madt(rsdp_address)?);
+    let madt = unsafe { &*(madt_address as *const Madt) };
+    let length = madt.length as usize - core::mem::size_of::<Madt>();
+    let base_address = madt_address + core::mem::size_of::<Madt>();
     Some(ApicIdList {
         base_address,
         length,
@@ -117,6 +182,117 @@ pub fn get_apic_id_list(rsdp_address: usize) -> Option<ApicIdList> {
     })
 }
 
+/// MADTの各レコード(Interrupt Controller Structure)のタイプと仮想アドレスをfに渡す
+fn for_each_madt_record(madt_address: usize, mut f: impl FnMut(u8, usize)) {
+    let madt = unsafe { &*(madt_address as *const Madt) };
+    let end_address = madt_address + madt.length as usize;
+    let mut entry_address = madt_address + core::mem::size_of::<Madt>();
+    while entry_address + 2 <= end_address {
+        let record_type = unsafe { *(entry_address as *const u8) };
+        let record_length = unsafe { *((entry_address + 1) as *const u8) } as usize;
+        if record_length < 2 {
+            break;
+        }
+        f(record_type, entry_address);
+        entry_address += record_length;
+    }
+}
+
+/// MADTに書かれたLocal APICの物理アドレス(タイプ5のオーバーライドがあればその値)
+pub fn get_local_apic_address(rsdp_address: usize) -> Option<usize> {
+    let madt_address = map_table(get_madt(rsdp_address)?);
+    let madt = unsafe { &*(madt_address as *const Madt) };
+    let mut address = madt.local_interrupt_controller_address as usize;
+    for_each_madt_record(madt_address, |record_type, entry_address| {
+        if record_type == 5 {
+            address =
+                unsafe { core::ptr::read_unaligned((entry_address + 4) as *const u64) } as usize;
+        }
+    });
+    Some(address)
+}
+
+/// ACPI Processor UIDがuidのCPUのAPIC IDをMADTのタイプ0・9から探す
+fn find_apic_id_by_uid(madt_address: usize, uid: u32) -> Option<u32> {
+    let mut apic_id = None;
+    for_each_madt_record(
+        madt_address,
+        |record_type, entry_address| match record_type {
+            0 if unsafe { *((entry_address + 2) as *const u8) } as u32 == uid => {
+                apic_id = Some(unsafe { *((entry_address + 3) as *const u8) } as u32);
+            }
+            9 if unsafe { core::ptr::read_unaligned((entry_address + 12) as *const u32) }
+                == uid =>
+            {
+                apic_id =
+                    Some(unsafe { core::ptr::read_unaligned((entry_address + 4) as *const u32) });
+            }
+            _ => {}
+        },
+    );
+    apic_id
+}
+
+/// MADTのLocal APIC NMI(タイプ4)とLocal x2APIC NMI(タイプ0xA)をnmi_listに追加する
+pub fn add_local_apic_nmis(rsdp_address: usize, nmi_list: &mut LocalApicNmiList) -> Option<()> {
+    let madt_address = map_table(get_madt(rsdp_address)?);
+    for_each_madt_record(madt_address, |record_type, entry_address| {
+        /* (ACPI Processor UID, 全CPUを表すUID, MPS INTI Flags, LINT番号) */
+        let (uid, all_uid, flags, lint_number) = match record_type {
+            4 => unsafe {
+                (
+                    *((entry_address + 2) as *const u8) as u32,
+                    0xff,
+                    core::ptr::read_unaligned((entry_address + 3) as *const u16),
+                    *((entry_address + 5) as *const u8),
+                )
+            },
+            0xa => unsafe {
+                (
+                    core::ptr::read_unaligned((entry_address + 4) as *const u32),
+                    0xffffffff,
+                    core::ptr::read_unaligned((entry_address + 2) as *const u16),
+                    *((entry_address + 8) as *const u8),
+                )
+            },
+            _ => return,
+        };
+        if uid == all_uid {
+            nmi_list.add(None, lint_number, flags);
+        } else if let Some(apic_id) = find_apic_id_by_uid(madt_address, uid) {
+            nmi_list.add(Some(apic_id), lint_number, flags);
+        }
+    });
+    Some(())
+}
+
+/// MADTのI/O APIC(タイプ1)とInterrupt Source Override(タイプ2)をio_apic_managerに追加する
+pub fn add_io_apics(rsdp_address: usize, io_apic_manager: &mut IoApicManager) -> Option<()> {
+    let madt_address = map_table(get_madt(rsdp_address)?);
+    for_each_madt_record(madt_address, |record_type, entry_address| {
+        match record_type {
+            1 => {
+                let id = unsafe { *((entry_address + 2) as *const u8) };
+                let address =
+                    unsafe { core::ptr::read_unaligned((entry_address + 4) as *const u32) }
+                        as usize;
+                let gsi_base =
+                    unsafe { core::ptr::read_unaligned((entry_address + 8) as *const u32) };
+                io_apic_manager.add_io_apic(id, address, gsi_base);
+            }
+            2 => {
+                /* バス(2byte目)は0(ISA)のみ */
+                let irq = unsafe { *((entry_address + 3) as *const u8) };
+                let gsi = unsafe { core::ptr::read_unaligned((entry_address + 4) as *const u32) };
+                let flags = unsafe { core::ptr::read_unaligned((entry_address + 8) as *const u16) };
+                io_apic_manager.add_interrupt_source_override(irq, gsi, flags);
+            }
+            _ => {}
+        }
+    });
+    Some(())
+}
+
 pub fn get_acpi_pm_timer(rsdp_address: usize) -> Option<AcpiPmTimer> {
     let (address, is_xsdt) = if let Some(xsdt_address) = get_xsdt(rsdp_address) {
         (xsdt_address, true)
@@ -126,10 +302,9 @@ pub fn get_acpi_pm_timer(rsdp_address: usize) -> Option<AcpiPmTimer> {
 
     let mut index = 0;
     while let Some(entry_address) = get_entry(address, index, is_xsdt) {
-        if unsafe { *(entry_address as *const [u8; 4]) }
-            == ['F' as u8, 'A' as u8, 'C' as u8, 'P' as u8]
-        {
-            let fadt = unsafe { &*(entry_address as *const FADT) };
+        let table = map_table(entry_address);
+        if unsafe { *(table as *const [u8; 4]) } == *b"FACP" {
+            let fadt = unsafe { &*(table as *const Fadt) };
             return Some(AcpiPmTimer::new(
                 fadt.pm_tmr_block as usize,
                 ((fadt.flags >> 8) & 1) != 0,
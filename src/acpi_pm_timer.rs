@@ -1,7 +1,7 @@
 //! ACPI PM Timer
 //!
 //! ACPIをサポートしているPCに搭載されている周波数3579545Hzのカウントアップタイマーです。
-//! 今回はコアの初期化の際のビジーウェイトに使用してます。
+//! コアの初期化の際のビジーウェイトと、Local APIC Timerの周波数の測定に使用してます。
 
 use core::arch::asm;
 
@@ -11,7 +11,7 @@ pub struct AcpiPmTimer {
 }
 
 impl AcpiPmTimer {
-    const FREQUENCY_HZ: usize = 3579545;
+    pub const FREQUENCY_HZ: usize = 3579545;
     pub const fn new(port: usize, is_32_bit_counter: bool) -> Self {
         Self {
             port,
@@ -23,13 +23,13 @@ impl AcpiPmTimer {
         Self::new(0, false)
     }
 
-    fn get_count(&self) -> usize {
+    pub fn get_count(&self) -> usize {
         if self.port == 0 {
             return 0;
         }
         let mut result: u32;
         unsafe { asm!("in eax, dx", in("dx") self.port, out("eax") result) };
-        if self.is_32_bit_counter == false {
+        if !self.is_32_bit_counter {
             result &= 0xffffff;
         }
         result as usize
@@ -40,7 +40,7 @@ impl AcpiPmTimer {
 
         if self.is_32_bit_counter {
             result
-        } else if overflow == false {
+        } else if !overflow {
             if result <= 0xffffff {
                 result
             } else {
@@ -59,6 +59,11 @@ impl AcpiPmTimer {
         }
     }
 
+    /// startからのカウントの増加量(カウンタの一周未満まで)
+    pub fn get_elapsed_count(&self, start: usize) -> usize {
+        self.get_count().wrapping_sub(start) & self.get_max_counter_value()
+    }
+
     #[inline(always)]
     pub fn busy_wait_ms(&self, ms: usize) {
         let start = self.get_count();
@@ -2,11 +2,22 @@
 
 use super::acpi::ApicIdList;
 use super::acpi_pm_timer::AcpiPmTimer;
-use super::local_apic::{get_apic_id, send_interrupt_command};
-use super::memory::MemoryManager;
-use super::MEMORY_MANAGER;
-
+use super::boot_option::BootOptions;
+use super::cpu_call::{init_cpu_call, CallQueue};
+use super::gdt::{create_gdt, get_boot_gdt, GlobalDescriptorTable};
+use super::interrupt::{enable_interrupt, load_idt};
+use super::local_apic::{
+    get_apic_id, init_x2apic_on_ap, is_x2apic_mode, send_interrupt_command, setup_local_apic,
+};
+use super::local_apic_timer::{init_local_apic_timer, LocalApicTimer};
+use super::paging::phys_to_virt;
+use super::tsc::check_tsc_sync_with_bsp;
+use super::tss::{create_tss, get_boot_tss, TaskStateSegmentWithIoMap};
+use super::{ACPI_PM_TIMER, LOCAL_APIC_NMI_LIST, MEMORY_MANAGER, PAGE_MANAGER, STACK_MANAGER, TSC};
+
+use alloc::boxed::Box;
 use core::arch::asm;
+use core::convert::TryFrom;
 use core::sync::atomic::AtomicBool;
 
 /// 各プロセッサが個別に持つ構造体
@@ -16,12 +27,44 @@ pub struct PerCpuData {
     #[allow(dead_code)]
     self_pointer: usize,
     local_apic_id: u32,
+    /// このCPUが読み込んでいるGDT
+    gdt: &'static GlobalDescriptorTable,
+    /// このCPUが読み込んでいるTSS
+    tss: &'static mut TaskStateSegmentWithIoMap,
+    pub local_apic_timer: LocalApicTimer,
+    /// このCPU宛ての関数呼び出しのキュー(init_cpu_callで設定)
+    pub call_queue: Option<&'static CallQueue>,
+}
+
+impl PerCpuData {
+    /// このCPUが読み込むGDT
+    pub fn get_gdt(&self) -> &'static GlobalDescriptorTable {
+        self.gdt
+    }
+
+    /// このCPUが読み込んでいるTSS
+    pub fn get_tss(&mut self) -> &mut TaskStateSegmentWithIoMap {
+        self.tss
+    }
 }
 
 /// APが起動したかどうかの確認用フラグ
 static AP_BOOT_COMPLETE_FLAG: AtomicBool = AtomicBool::new(false);
+/// 起動中のAPが読み込むGDTとTSS(APは一つずつ起動するので一組だけ用意する)
+static mut AP_GDT_AND_TSS: Option<(
+    &'static GlobalDescriptorTable,
+    &'static mut TaskStateSegmentWithIoMap,
+)> = None;
+
+/// BSP用のPerCpuDataを作成する
+///
+/// BSPはboot.sのGDTとTSSをそのまま使います。ヒープとLocal APICの初期化後に一度だけ呼び出してください。
+pub fn init_per_cpu_data_on_bsp() {
+    let per_cpu_data = create_per_cpu_data(get_boot_gdt(), unsafe { get_boot_tss() });
+    per_cpu_data.local_apic_id = get_apic_id();
+}
 
-pub fn init_ap(apic_id_list: ApicIdList, pm_timer: &AcpiPmTimer) {
+pub fn init_ap(apic_id_list: ApicIdList, pm_timer: &AcpiPmTimer, boot_options: &BootOptions) {
     /* ap_boot.s */
     extern "C" {
         fn ap_entry();
@@ -31,50 +74,59 @@ pub fn init_ap(apic_id_list: ApicIdList, pm_timer: &AcpiPmTimer) {
     let ap_entry_address = ap_entry as *const fn() as usize;
     let ap_entry_end_address = ap_entry_end as *const fn() as usize;
 
-    let boot_code_address = unsafe {
-        MEMORY_MANAGER
-            .alloc_with_align(ap_entry_end_address - ap_entry_address, 0x1000)
-            .unwrap()
-    };
-    assert!(
-        boot_code_address <= 0xff000,
-        "Address :{:#X}",
-        boot_code_address
-    );
+    /* SIPIのベクタはアドレスの12~19bitなので1MiB未満で4KiBに揃っている必要がある */
+    let (boot_code_address, boot_code_area_size) = unsafe { MEMORY_MANAGER.get_real_mode_area() }
+        .expect("Cannot allocate memory for the boot code of application processors");
+    assert!(ap_entry_end_address - ap_entry_address <= boot_code_area_size);
+    assert!(boot_code_address + boot_code_area_size <= 0x100000);
+    assert_eq!(boot_code_address & 0xfff, 0);
 
-    let vector = ((boot_code_address >> 12) & 0xff) as u8;
+    let vector = u8::try_from(boot_code_address >> 12).expect("Invalid SIPI vector");
     /* 起動用のアセンブリコードをコピー */
     unsafe {
         core::ptr::copy_nonoverlapping(
             ap_entry_address as *const u8,
-            boot_code_address as *mut u8,
+            phys_to_virt(boot_code_address) as *mut u8,
             ap_entry_end_address - ap_entry_address,
         )
     };
 
-    /* BSP用のPerCpuDataを作成し、local_apic_idをセット */
-    let mut per_cpu_data = create_per_cpu_data(unsafe { &MEMORY_MANAGER });
-    let bsp_apic_id = get_apic_id() as u32;
-    per_cpu_data.local_apic_id = bsp_apic_id;
+    let bsp_apic_id = get_per_cpu_data().local_apic_id;
 
+    let max_cpus = boot_options.get_max_cpus();
     let mut num_of_cpu = 1usize;
     'ap_init_loop: for apic_id in apic_id_list {
         if apic_id == bsp_apic_id {
             continue;
         }
-        num_of_cpu += 1;
-        if apic_id > 0xff {
-            panic!("Please enable x2APIC");
+        if num_of_cpu >= max_cpus {
+            pr_info!("Skip CPU(APIC ID: {}) by the boot option", apic_id);
+            continue;
+        }
+        if apic_id > 0xff && !is_x2apic_mode() {
+            pr_warn!(
+                "Skip CPU(APIC ID: {}) because x2APIC is not available",
+                apic_id
+            );
+            continue;
         }
+        num_of_cpu += 1;
 
-        let stack_size = 0x8000;
-        let stack = unsafe { MEMORY_MANAGER.alloc_with_align(stack_size, 0x10).unwrap() };
+        let stack = unsafe { STACK_MANAGER.alloc_stack(num_of_cpu - 1, boot_options.stack_size) }
+            .expect("Cannot allocate the stack for the application processor");
         /* スタックのアドレスを起動するAPが取得できるようにメモする */
         unsafe {
-            *(((&mut ap_os_stack_address as *mut _ as usize) - ap_entry_address + boot_code_address)
-                as *mut u64) = (stack + stack_size) as u64
+            *(phys_to_virt(
+                (&mut ap_os_stack_address as *mut _ as usize) - ap_entry_address
+                    + boot_code_address,
+            ) as *mut u64) = stack.stack_top as u64
         };
 
+        /* GDTとTSSもBSPで用意しておき、APはap_boot_mainで読み込む */
+        let tss = create_tss(num_of_cpu - 1);
+        let gdt = create_gdt(tss);
+        unsafe { AP_GDT_AND_TSS = Some((gdt, tss)) };
+
         AP_BOOT_COMPLETE_FLAG.store(false, core::sync::atomic::Ordering::Relaxed);
 
         send_interrupt_command(apic_id, 0b101 /*INIT*/, 1, 1 /*Assert*/, 0);
@@ -90,10 +142,23 @@ pub fn init_ap(apic_id_list: ApicIdList, pm_timer: &AcpiPmTimer) {
         pm_timer.busy_wait_us(200);
 
         send_interrupt_command(apic_id, 0b110 /* Startup IPI*/, 0, 1, vector);
-        for _wait in 0..5000
-        /* APの初期化完了まで5秒待つ */
+        for _wait in 0..boot_options.ap_timeout_ms
+        /* APの初期化完了まで待つ(既定では5秒) */
         {
             if AP_BOOT_COMPLETE_FLAG.load(core::sync::atomic::Ordering::Relaxed) {
+                /* APはフラグを立てた直後にcheck_tsc_sync_with_bspを呼び出している */
+                match unsafe { TSC.check_sync_with_ap() } {
+                    Some(0) => {}
+                    Some(warp) => pr_warn!(
+                        "TSC of CPU(APIC ID: {}) is not synchronized with BSP (Warp: {})",
+                        apic_id,
+                        warp
+                    ),
+                    None => pr_warn!(
+                        "CPU(APIC ID: {}) did not finish the TSC synchronization check",
+                        apic_id
+                    ),
+                }
                 continue 'ap_init_loop;
             }
             pm_timer.busy_wait_ms(1);
@@ -102,38 +167,60 @@ pub fn init_ap(apic_id_list: ApicIdList, pm_timer: &AcpiPmTimer) {
     }
 
     if num_of_cpu != 1 {
-        println!("Found {} CPUs", num_of_cpu);
+        pr_info!("Found {} CPUs", num_of_cpu);
+        if !unsafe { TSC.is_synchronized() } {
+            pr_warn!("The monotonic clock may go backwards when compared across CPUs");
+        }
     }
 }
 
 #[no_mangle]
 extern "C" fn ap_boot_main() -> ! {
-    let mut per_cpu_data = create_per_cpu_data(unsafe { &MEMORY_MANAGER });
-    per_cpu_data.local_apic_id = get_apic_id() as u32;
-    drop(per_cpu_data);
+    let (gdt, tss) =
+        unsafe { AP_GDT_AND_TSS.take() }.expect("GDT and TSS for the AP are not prepared");
+    let per_cpu_data = create_per_cpu_data(gdt, tss);
+    /* IDTのISTを使う例外に備え、IDTより先にTSSを読み込む */
+    per_cpu_data.get_gdt().load();
+    load_idt();
+    init_x2apic_on_ap();
+    per_cpu_data.local_apic_id = get_apic_id();
+    setup_local_apic(unsafe { &LOCAL_APIC_NMI_LIST });
+    init_local_apic_timer(unsafe { &ACPI_PM_TIMER });
     println!(
         "Hello! Local Apic id = {}",
         get_per_cpu_data().local_apic_id
     );
+    init_cpu_call();
+    unsafe { PAGE_MANAGER.activate() };
     AP_BOOT_COMPLETE_FLAG.store(true, core::sync::atomic::Ordering::Relaxed);
+    check_tsc_sync_with_bsp();
+    enable_interrupt();
     loop {
         unsafe { asm!("hlt") };
     }
 }
 
-fn create_per_cpu_data(memory_manager: &MemoryManager) -> &'static mut PerCpuData {
-    let address = memory_manager
-        .alloc(core::mem::size_of::<PerCpuData>())
-        .unwrap();
-    let mut d = unsafe { &mut *(address as *mut PerCpuData) };
+fn create_per_cpu_data(
+    gdt: &'static GlobalDescriptorTable,
+    tss: &'static mut TaskStateSegmentWithIoMap,
+) -> &'static mut PerCpuData {
+    let d = Box::leak(Box::new(PerCpuData {
+        self_pointer: 0,
+        local_apic_id: 0,
+        gdt,
+        tss,
+        local_apic_timer: LocalApicTimer::new(),
+        call_queue: None,
+    }));
+    let address = d as *mut PerCpuData as usize;
     d.self_pointer = address;
     let edx: u32 = (address >> 32) as u32;
     let eax: u32 = address as u32;
     unsafe { asm!("wrmsr", in("eax") eax, in("edx") edx, in("ecx") 0xC0000101u32) };
-    return d;
+    d
 }
 
-fn get_per_cpu_data() -> &'static mut PerCpuData {
+pub fn get_per_cpu_data() -> &'static mut PerCpuData {
     let address: usize;
     unsafe {
         asm!("mov {}, gs:0",out(reg) address);
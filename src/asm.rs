@@ -1,5 +1,27 @@
 use core::arch::global_asm;
 
+/* カーネルの仮想アドレスと物理アドレスの差(paging::KERNEL_VMA_OFFSETと同じ値) */
+#[cfg(feature = "higher_half")]
+macro_rules! kernel_vma_offset {
+    () => {
+        ".set KERNEL_VMA_OFFSET, 0xffffffff80000000\n"
+    };
+}
+
+#[cfg(not(feature = "higher_half"))]
+macro_rules! kernel_vma_offset {
+    () => {
+        ".set KERNEL_VMA_OFFSET, 0\n"
+    };
+}
+
 global_asm!(include_str!("asm/boot_header.s"), options(att_syntax));
-global_asm!(include_str!("asm/boot.s"), options(att_syntax));
-global_asm!(include_str!("asm/ap_boot.s"), options(att_syntax));
+global_asm!(
+    concat!(kernel_vma_offset!(), include_str!("asm/boot.s")),
+    options(att_syntax)
+);
+global_asm!(
+    concat!(kernel_vma_offset!(), include_str!("asm/ap_boot.s")),
+    options(att_syntax)
+);
+global_asm!(include_str!("asm/interrupt.s"), options(att_syntax));
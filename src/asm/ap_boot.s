@@ -1,7 +1,7 @@
 
 .global ap_entry, ap_entry_end, ap_os_stack_address
 
-.extern main_code_segment_descriptor, gdtr0, pml4
+.extern main_code_segment_descriptor, gdtr0, gdtr0_physical, pml4
 .extern ap_boot_main
 
 .section .data
@@ -45,7 +45,7 @@ ap_init_long_mode:
     mov    $(ljmpl_64_address - ap_entry), %eax
     add     %ebx, (%ebx, %eax)
 
-    mov     $pml4, %eax
+    mov     $(pml4 - KERNEL_VMA_OFFSET), %eax
     mov     %eax, %cr3
     mov     %cr4, %eax
     or      $(1 << 5), %eax
@@ -55,8 +55,8 @@ ap_init_long_mode:
     or      $(1 << 8 | 1 << 11), %eax
     wrmsr                               /* Set LME and NXE flags */
     mov     %cr0, %eax
-    or      $(1 << 31 | 1), %eax        /* Set PG flag */
-    lgdt    gdtr0
+    or      $(1 << 31 | 1 << 16 | 1), %eax /* Set PG and WP flags */
+    lgdt    (gdtr0_physical - KERNEL_VMA_OFFSET)
     mov     %eax, %cr0
 
     /* Long JMP */
@@ -78,7 +78,10 @@ ap_init_x86_64:
     mov     $(ap_os_stack_address - ap_entry), %eax
     add     %ebx, %eax      /* EBXがベースアドレスを保持してる */
     mov     (%eax), %rsp
-    lea     ap_boot_main, %rax
+    /* このコードはコピーされて実行されるのでRIP相対ではなく絶対アドレスを使う */
+    movabs  $gdtr0, %rax
+    lgdt    (%rax)          /* GDTを仮想アドレスで読み込み直す */
+    movabs  $ap_boot_main, %rax
     jmp    *%rax            /* "*"は絶対ジャンプ */
 
 
@@ -4,15 +4,16 @@
 .equ STACK_SIZE, 0x8000
 .equ IO_MAP_SIZE,0xffff
 
-.global boot_entry, main_code_segment_descriptor, gdtr0, pml4
+.global boot_entry, main_code_segment_descriptor, gdtr0, gdtr0_physical, pml4, gdt, tss
 .extern boot_main
 
 .section .text
 .align 4
 
 .code32
+/* ページングが有効になるまでは物理アドレス(シンボル - KERNEL_VMA_OFFSET)でアクセスする */
 boot_entry:
-  mov   $(stack + STACK_SIZE), %esp
+  mov   $(stack + STACK_SIZE - KERNEL_VMA_OFFSET), %esp
 
   push  $0
   popfd
@@ -29,13 +30,6 @@ boot_entry:
   out  %al, $0xa1
   cli
 
-  /* TSSセグメント記述子にアドレスを記入 */
-  mov   $tss, %eax
-  mov   $tss_descriptor_address, %ebp
-  mov   %ax, 2(%ebp)
-  shr   $16, %eax
-  mov   %al, 4(%ebp)
-  mov   %ah, 7(%ebp)
   /* ロングモード対応か確認 */
   pushfd
   pop   %eax
@@ -64,13 +58,14 @@ boot_entry:
   /* Paging */
   /* 2MiBページングを有効化 */
   /* PML4->PDP->PD */
-  /* 先頭4GiBを仮想アドレス = 物理アドレスでマップ */
+  /* 先頭4GiBを仮想アドレス = 物理アドレスと
+     ダイレクトマップ(0xffff800000000000~)の両方にマップ */
   xor   %ecx,  %ecx
 pde_setup:
   mov   $0x200000, %eax
   mul   %ecx
   or    $0b10000011, %eax
-  mov   %eax, pd(,%ecx, 8)
+  mov   %eax, (pd - KERNEL_VMA_OFFSET)(,%ecx, 8)
   inc   %ecx
   cmp   $2048, %ecx
   jne   pde_setup
@@ -79,23 +74,34 @@ pde_setup:
 pdpte_setup:
   mov   $4096, %eax
   mul   %ecx
-  add   $pd, %eax
+  add   $(pd - KERNEL_VMA_OFFSET), %eax
   or    $0b11, %eax
-  mov   %eax, pdpt(,%ecx, 8)
+  mov   %eax, (pdpt - KERNEL_VMA_OFFSET)(,%ecx, 8)
   inc   %ecx
   cmp   $4, %ecx
   jne   pdpte_setup
 
 /* pml4_setup: */
-  mov   $pdpt, %eax
+  mov   $(pdpt - KERNEL_VMA_OFFSET), %eax
   or    $0b11, %eax
-  mov   %eax, (pml4)
+  mov   %eax, (pml4 - KERNEL_VMA_OFFSET)
+  mov   %eax, (pml4 - KERNEL_VMA_OFFSET + 256 * 8)
+
+.if KERNEL_VMA_OFFSET
+  /* カーネルの仮想アドレス(KERNEL_VMA_OFFSET~)に先頭1GiBをマップ */
+  mov   $(pd - KERNEL_VMA_OFFSET), %eax
+  or    $0b11, %eax
+  mov   %eax, (kernel_pdpt - KERNEL_VMA_OFFSET + ((KERNEL_VMA_OFFSET >> 30) & 0x1ff) * 8)
+  mov   $(kernel_pdpt - KERNEL_VMA_OFFSET), %eax
+  or    $0b11, %eax
+  mov   %eax, (pml4 - KERNEL_VMA_OFFSET + ((KERNEL_VMA_OFFSET >> 39) & 0x1ff) * 8)
+.endif
 
 /* setup_64: */
   /* CR3にPML4のアドレスをセットし、
      CR4のPAEフラグとMSR(0xc0000080)のLMEとNXEをセット、
-     最後にページングを有効化しGDTをセット */
-  mov   $pml4, %eax
+     最後にページングと書き込み保護(WP)を有効化しGDTをセット */
+  mov   $(pml4 - KERNEL_VMA_OFFSET), %eax
   mov   %eax, %cr3
   mov   %cr4, %eax
   or    $(1 << 5), %eax
@@ -105,10 +111,10 @@ pdpte_setup:
   or    $(1 << 8 | 1 << 11), %eax
   wrmsr
   mov   %cr0, %eax
-  or    $(1 << 31 | 1), %eax
-  lgdt  gdtr0
+  or    $(1 << 31 | 1 << 16 | 1), %eax
+  lgdt  (gdtr0_physical - KERNEL_VMA_OFFSET)
   mov   %eax, %cr0
-  ljmp $main_code_segment_descriptor, $jump_to_rust
+  ljmp $main_code_segment_descriptor, $(jump_to_rust - KERNEL_VMA_OFFSET)
 
 
 fin:
@@ -119,15 +125,35 @@ fin:
 .code64
 
 jump_to_rust:
+  /* 仮想アドレス上のカーネルへ移動し、GDTを仮想アドレスで読み込み直す */
+  movabs $jump_to_virtual_address, %rax
+  jmp   *%rax
+
+jump_to_virtual_address:
+  lgdt  gdtr0(%rip)
   xor   %ax, %ax
   mov   %ax, %es
   mov   %ax, %ss
   mov   %ax, %ds
   mov   %ax, %fs
   mov   %ax, %gs
+
+  /* TSSセグメント記述子にアドレスを記入 */
+  lea   tss(%rip), %rax
+  lea   tss_descriptor_address(%rip), %rbp
+  mov   %ax, 2(%rbp)
+  shr   $16, %rax
+  mov   %al, 4(%rbp)
+  mov   %ah, 7(%rbp)
+  shr   $16, %rax
+  mov   %eax, 8(%rbp)
   mov   $tss_descriptor, %ax
   ltr   %ax
 
+  /* スタックも仮想アドレスに切り替える */
+  movabs $KERNEL_VMA_OFFSET, %rax
+  add   %rax, %rsp
+
   pop   %rdi
   jmp   boot_main
   
@@ -141,9 +167,12 @@ jump_to_rust:
 /* PAGE DIRECTPRY POINTER TABLE (8byte * 512[4 entries are used]) */
 .comm pdpt, 0x1000, 0x1000
 
-/* PML4 (8byte * 512[1 entry is used]) */
+/* PML4 (8byte * 512[2 or 3 entries are used]) */
 .comm pml4, 0x1000, 0x1000
 
+/* カーネルの仮想アドレス用のPAGE DIRECTPRY POINTER TABLE (8byte * 512[1 entry is used]) */
+.comm kernel_pdpt, 0x1000, 0x1000
+
 tss:
   .rept     25
     .long    0
@@ -173,11 +202,11 @@ gdt:
 
 tss_descriptor_address:
 .equ  tss_descriptor, tss_descriptor_address - gdt
-    .word    (tss_end - tss) & 0xffff               /* Limit(Low) */
+    .word    (tss_end - tss - 1) & 0xffff           /* Limit(Low) */
     .word    0                                      /* Base(Low) */
     .byte    0                                      /* Base(middle) */
     .byte    0b10001001                             /* 64bit TSS + DPL:0 + P:1 */
-    .byte    ((tss_end - tss) & 0xff0000) >> 0x10   /* Limit(High)+Granularity */
+    .byte    ((tss_end - tss - 1) & 0xff0000) >> 0x10 /* Limit(High)+Granularity */
     .byte    0                                      /* Base(Middle high) */
     .long    0                                      /* Base(High) */
     .word    0                                      /* Reserved */
@@ -187,3 +216,8 @@ gdtr0:
   .word    . - gdt - 1                  /* The byte size of descriptors */
   .quad    gdt
 
+/* ページングを有効にする前に読み込むためのGDTR */
+gdtr0_physical:
+  .word    gdtr0 - gdt - 1
+  .quad    gdt - KERNEL_VMA_OFFSET
+
@@ -0,0 +1,199 @@
+/* CPU例外と割り込みの入口
+   エラーコードを積まない例外と割り込みではダミーの0を積んでスタックの形をそろえ、
+   ベクタ番号と汎用レジスタを保存してからexception_handler(0~31番)か
+   interrupt_handler(32~255番)を呼び出す */
+
+.global exception_entry_table, interrupt_entry_table
+.extern exception_handler, interrupt_handler
+
+.section .text
+
+.macro common_entry name, handler
+\name:
+  push  %rax
+  push  %rbx
+  push  %rcx
+  push  %rdx
+  push  %rsi
+  push  %rdi
+  push  %rbp
+  push  %r8
+  push  %r9
+  push  %r10
+  push  %r11
+  push  %r12
+  push  %r13
+  push  %r14
+  push  %r15
+  mov   %rsp, %rdi          /* 第1引数: 保存したレジスタ(ExceptionFrame)のアドレス */
+  mov   %rsp, %rbx
+  and   $-16, %rsp          /* 呼び出し前にスタックを16byteに揃える */
+  cld
+  call  \handler
+  mov   %rbx, %rsp
+  pop   %r15
+  pop   %r14
+  pop   %r13
+  pop   %r12
+  pop   %r11
+  pop   %r10
+  pop   %r9
+  pop   %r8
+  pop   %rbp
+  pop   %rdi
+  pop   %rsi
+  pop   %rdx
+  pop   %rcx
+  pop   %rbx
+  pop   %rax
+  add   $16, %rsp           /* ベクタ番号とエラーコード */
+  iretq
+.endm
+
+.macro exception_entry vector, has_error_code
+exception_entry_\vector:
+.if \has_error_code == 0
+  push  $0
+.endif
+  push  $\vector
+  jmp   exception_common
+.endm
+
+  exception_entry 0, 0    /* #DE */
+  exception_entry 1, 0    /* #DB */
+  exception_entry 2, 0    /* NMI */
+  exception_entry 3, 0    /* #BP */
+  exception_entry 4, 0    /* #OF */
+  exception_entry 5, 0    /* #BR */
+  exception_entry 6, 0    /* #UD */
+  exception_entry 7, 0    /* #NM */
+  exception_entry 8, 1    /* #DF */
+  exception_entry 9, 0
+  exception_entry 10, 1   /* #TS */
+  exception_entry 11, 1   /* #NP */
+  exception_entry 12, 1   /* #SS */
+  exception_entry 13, 1   /* #GP */
+  exception_entry 14, 1   /* #PF */
+  exception_entry 15, 0
+  exception_entry 16, 0   /* #MF */
+  exception_entry 17, 1   /* #AC */
+  exception_entry 18, 0   /* #MC */
+  exception_entry 19, 0   /* #XM */
+  exception_entry 20, 0   /* #VE */
+  exception_entry 21, 1   /* #CP */
+  exception_entry 22, 0
+  exception_entry 23, 0
+  exception_entry 24, 0
+  exception_entry 25, 0
+  exception_entry 26, 0
+  exception_entry 27, 0
+  exception_entry 28, 0   /* #HV */
+  exception_entry 29, 1   /* #VC */
+  exception_entry 30, 1   /* #SX */
+  exception_entry 31, 0
+
+.macro interrupt_entry vector
+interrupt_entry_\vector:
+  push  $0
+  push  $\vector
+  jmp   interrupt_common
+.endm
+
+.irp vector, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47
+  interrupt_entry \vector
+.endr
+.irp vector, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63
+  interrupt_entry \vector
+.endr
+.irp vector, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79
+  interrupt_entry \vector
+.endr
+.irp vector, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95
+  interrupt_entry \vector
+.endr
+.irp vector, 96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111
+  interrupt_entry \vector
+.endr
+.irp vector, 112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127
+  interrupt_entry \vector
+.endr
+.irp vector, 128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143
+  interrupt_entry \vector
+.endr
+.irp vector, 144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157, 158, 159
+  interrupt_entry \vector
+.endr
+.irp vector, 160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175
+  interrupt_entry \vector
+.endr
+.irp vector, 176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191
+  interrupt_entry \vector
+.endr
+.irp vector, 192, 193, 194, 195, 196, 197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207
+  interrupt_entry \vector
+.endr
+.irp vector, 208, 209, 210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222, 223
+  interrupt_entry \vector
+.endr
+.irp vector, 224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235, 236, 237, 238, 239
+  interrupt_entry \vector
+.endr
+.irp vector, 240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255
+  interrupt_entry \vector
+.endr
+
+  common_entry exception_common, exception_handler
+  common_entry interrupt_common, interrupt_handler
+
+.section .rodata
+
+.align 8
+exception_entry_table:
+.irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+  .quad exception_entry_\vector
+.endr
+
+.align 8
+interrupt_entry_table:
+.irp vector, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 112, 113, 114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139, 140, 141, 142, 143
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 144, 145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157, 158, 159
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170, 171, 172, 173, 174, 175
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 176, 177, 178, 179, 180, 181, 182, 183, 184, 185, 186, 187, 188, 189, 190, 191
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 192, 193, 194, 195, 196, 197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 208, 209, 210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222, 223
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235, 236, 237, 238, 239
+  .quad interrupt_entry_\vector
+.endr
+.irp vector, 240, 241, 242, 243, 244, 245, 246, 247, 248, 249, 250, 251, 252, 253, 254, 255
+  .quad interrupt_entry_\vector
+.endr
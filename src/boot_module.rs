@@ -0,0 +1,72 @@
+//! ブートモジュール管理用モジュール
+//!
+//! grub.cfgの`module2`で読み込まれたファイルを記録し、名前で検索できるようにします。
+//! `module2 /boot/grub/fonts/unicode.pf2 font.pf2 args...`の場合、
+//! 最初の単語("font.pf2")を名前、残りを引数として扱います。
+
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    pub name: &'static str,
+    pub arguments: &'static str,
+    pub start_address: usize,
+    pub end_address: usize,
+}
+
+pub struct BootModuleList {
+    modules: [Option<BootModule>; Self::MAX_NUM_OF_MODULES],
+    num_of_modules: usize,
+}
+
+impl BootModule {
+    pub fn new(string: &'static str, start_address: usize, end_address: usize) -> Self {
+        let string = string.trim();
+        let (name, arguments) = if let Some(index) = string.find(char::is_whitespace) {
+            (&string[..index], string[index..].trim_start())
+        } else {
+            (string, "")
+        };
+        Self {
+            name,
+            arguments,
+            start_address,
+            end_address,
+        }
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.end_address - self.start_address
+    }
+}
+
+impl BootModuleList {
+    pub const MAX_NUM_OF_MODULES: usize = 16;
+
+    pub const fn const_new() -> Self {
+        Self {
+            modules: [None; Self::MAX_NUM_OF_MODULES],
+            num_of_modules: 0,
+        }
+    }
+
+    /// 登録できなかった場合はfalseを返す
+    pub fn add(&mut self, module: BootModule) -> bool {
+        if self.num_of_modules >= Self::MAX_NUM_OF_MODULES
+            || module.end_address < module.start_address
+        {
+            return false;
+        }
+        self.modules[self.num_of_modules] = Some(module);
+        self.num_of_modules += 1;
+        true
+    }
+
+    pub fn find(&self, name: &str) -> Option<&BootModule> {
+        self.iter().find(|m| m.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BootModule> {
+        self.modules[..self.num_of_modules]
+            .iter()
+            .filter_map(|m| m.as_ref())
+    }
+}
@@ -0,0 +1,214 @@
+//! カーネルコマンドライン解析用モジュール
+//!
+//! Multiboot2のboot command lineタグで渡された文字列を解析し、起動時の設定として保持します。
+//! grub.cfgの`multiboot2 /boot/kernel.elf maxcpus=2 loglevel=8`のように指定します。
+//! 知らないオプションや不正な値は無視し、既定値のままにします。
+
+use super::print::LOG_LEVEL_DEFAULT;
+use super::stack::DEFAULT_STACK_SIZE;
+
+/// 出力先の設定
+#[derive(Clone, Copy)]
+pub struct ConsoleSetting {
+    pub serial_port: bool,
+    pub graphic: bool,
+}
+
+pub struct BootOptions {
+    /// 起動するCPUの最大数(BSPを含む)
+    pub max_cpus: Option<usize>,
+    /// APを起動しない
+    pub no_smp: bool,
+    pub console: ConsoleSetting,
+    /// この値より小さいレベルのメッセージのみ表示する
+    pub log_level: u8,
+    /// AP一つあたりの起動完了を待つ時間
+    pub ap_timeout_ms: usize,
+    /// フレームバッファへの出力を行わない
+    pub no_frame_buffer: bool,
+    /// 使用する物理メモリの上限アドレス
+    pub memory_limit: Option<usize>,
+    /// 各CPUのカーネルスタックの大きさ
+    pub stack_size: usize,
+}
+
+impl BootOptions {
+    const DEFAULT_AP_TIMEOUT_MS: usize = 5000;
+
+    pub const fn const_new() -> Self {
+        Self {
+            max_cpus: None,
+            no_smp: false,
+            console: ConsoleSetting {
+                serial_port: true,
+                graphic: true,
+            },
+            log_level: LOG_LEVEL_DEFAULT,
+            ap_timeout_ms: Self::DEFAULT_AP_TIMEOUT_MS,
+            no_frame_buffer: false,
+            memory_limit: None,
+            stack_size: DEFAULT_STACK_SIZE,
+        }
+    }
+
+    pub fn parse(command_line: &str) -> Self {
+        let mut options = Self::const_new();
+        let mut is_console_specified = false;
+
+        for option in command_line.split_whitespace() {
+            let (key, value) = if let Some(index) = option.find('=') {
+                (&option[..index], Some(&option[(index + 1)..]))
+            } else {
+                (option, None)
+            };
+            match (key, value) {
+                ("nosmp", None) => {
+                    options.no_smp = true;
+                }
+                ("noframebuffer", None) => {
+                    options.no_frame_buffer = true;
+                }
+                ("maxcpus", Some(v)) => {
+                    if let Ok(n) = v.parse::<usize>() {
+                        options.max_cpus = Some(n.max(1));
+                    }
+                }
+                ("loglevel", Some(v)) => {
+                    if let Ok(l) = v.parse::<u8>() {
+                        options.log_level = l;
+                    }
+                }
+                ("ap_timeout_ms", Some(v)) => {
+                    if let Ok(t) = v.parse::<usize>() {
+                        options.ap_timeout_ms = t;
+                    }
+                }
+                ("mem", Some(v)) => {
+                    if let Some(size) = parse_size(v).filter(|s| *s != 0) {
+                        options.memory_limit = Some(size);
+                    }
+                }
+                ("stack_size", Some(v)) => {
+                    if let Some(size) = parse_size(v).filter(|s| *s != 0) {
+                        options.stack_size = size;
+                    }
+                }
+                ("console", Some(v)) => {
+                    /* console=は複数指定でき、指定されたものだけを有効にする */
+                    /* 知らない名前だけの場合は何も表示されなくなるのを防ぐため既定値のままにする */
+                    for c in v.split(',') {
+                        let (serial_port, graphic) = match c {
+                            "serial" | "ttyS0" => (true, false),
+                            "graphic" | "fb" | "tty0" => (false, true),
+                            _ => {
+                                pr_warn!("Unknown console: {}", c);
+                                continue;
+                            }
+                        };
+                        if !is_console_specified {
+                            options.console = ConsoleSetting {
+                                serial_port: false,
+                                graphic: false,
+                            };
+                            is_console_specified = true;
+                        }
+                        options.console.serial_port |= serial_port;
+                        options.console.graphic |= graphic;
+                    }
+                }
+                _ => {}
+            }
+        }
+        options
+    }
+
+    /// 実際に起動するCPUの数の上限
+    pub fn get_max_cpus(&self) -> usize {
+        if self.no_smp {
+            1
+        } else {
+            self.max_cpus.unwrap_or(usize::MAX)
+        }
+    }
+}
+
+/// "512M"のようにK/M/Gの接尾辞がついたサイズを解析する
+fn parse_size(s: &str) -> Option<usize> {
+    let (number, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..(s.len() - 1)], 10),
+        b'M' | b'm' => (&s[..(s.len() - 1)], 20),
+        b'G' | b'g' => (&s[..(s.len() - 1)], 30),
+        _ => (s, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        let options = BootOptions::parse(
+            "maxcpus=2 loglevel=8 ap_timeout_ms=100 noframebuffer stack_size=32K unknown=1",
+        );
+        assert_eq!(options.max_cpus, Some(2));
+        assert_eq!(options.get_max_cpus(), 2);
+        assert_eq!(options.log_level, 8);
+        assert_eq!(options.ap_timeout_ms, 100);
+        assert!(options.no_frame_buffer);
+        assert_eq!(options.stack_size, 32 << 10);
+        assert!(!options.no_smp);
+    }
+
+    #[test]
+    fn ignore_invalid_values() {
+        let options = BootOptions::parse("maxcpus=abc loglevel=-1 stack_size=0 nosmp=1");
+        assert_eq!(options.max_cpus, None);
+        assert_eq!(options.log_level, LOG_LEVEL_DEFAULT);
+        assert_eq!(options.stack_size, DEFAULT_STACK_SIZE);
+        assert!(!options.no_smp);
+        assert_eq!(BootOptions::parse("nosmp maxcpus=4").get_max_cpus(), 1);
+    }
+
+    #[test]
+    fn parse_memory_limit() {
+        assert_eq!(BootOptions::parse("").memory_limit, None);
+        assert_eq!(BootOptions::parse("mem=512M").memory_limit, Some(512 << 20));
+        assert_eq!(BootOptions::parse("mem=0x1000").memory_limit, None);
+        assert_eq!(BootOptions::parse("mem=0").memory_limit, None);
+    }
+
+    #[test]
+    fn console_selects_only_specified_outputs() {
+        let options = BootOptions::parse("console=serial");
+        assert!(options.console.serial_port);
+        assert!(!options.console.graphic);
+
+        let options = BootOptions::parse("console=ttyS0 console=fb");
+        assert!(options.console.serial_port);
+        assert!(options.console.graphic);
+    }
+
+    #[test]
+    fn console_ignores_unknown_names() {
+        /* 知らない名前だけなら既定の出力先のまま */
+        let options = BootOptions::parse("console=seiral");
+        assert!(options.console.serial_port);
+        assert!(options.console.graphic);
+
+        let options = BootOptions::parse("console=foo,graphic");
+        assert!(!options.console.serial_port);
+        assert!(options.console.graphic);
+    }
+
+    #[test]
+    fn parse_size_suffix() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("16k"), Some(16 << 10));
+        assert_eq!(parse_size("2M"), Some(2 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size(""), None);
+    }
+}
@@ -0,0 +1,284 @@
+//! CPU間の関数呼び出し
+//!
+//! 他のCPUのキューに関数を積み、IPIを送って実行してもらいます。
+//! 同期版は実行が終わるまで待ち、非同期版は待たずにCallHandleを返します。
+//! 待っている間も自分宛ての要求を処理するので、CPU同士が同時に呼び出し合ってもデッドロックしません。
+//! 一定時間内に実行を始めなかったCPUの分は取り消し、CallError::Timeoutを返します。
+
+use super::ap::get_per_cpu_data;
+use super::interrupt::{set_interrupt_handler, ExceptionFrame};
+use super::local_apic::{get_apic_id, send_interrupt_command};
+use super::spin_lock::SpinLock;
+use super::tsc::read_tsc;
+use super::TSC;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// 関数呼び出し用のIPIのベクタ
+pub const CALL_FUNCTION_VECTOR: u8 = 0xfd;
+/// 呼び出し先のCPUが実行を始めるまで待つ時間
+const CALL_TIMEOUT_MS: u64 = 1000;
+
+type CallFunction = dyn Fn() + Send + Sync;
+
+struct CallRequest {
+    func: Box<CallFunction>,
+    /// まだ実行を終えていないCPUの数
+    num_of_remaining_cpus: AtomicUsize,
+}
+
+/// 各CPU宛ての呼び出し要求のキュー
+pub struct CallQueue {
+    apic_id: u32,
+    requests: SpinLock<VecDeque<Arc<CallRequest>>>,
+}
+
+/// 非同期版の呼び出しの完了を確認するためのハンドル
+pub struct CallHandle {
+    request: Arc<CallRequest>,
+    /// 要求を積んだキュー(タイムアウト時の取り消し用)
+    queues: Vec<&'static CallQueue>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CallError {
+    /// 指定したAPIC IDのCPUがinit_cpu_callを呼び出していない
+    CpuNotFound,
+    /// 一部のCPUがCALL_TIMEOUT_MS以内に実行を始めなかった(そのCPUでは実行されません)
+    Timeout,
+}
+
+/// init_cpu_callを呼び出したCPUのキュー
+static CALL_QUEUES: SpinLock<Vec<&'static CallQueue>> = SpinLock::new(Vec::new());
+
+impl CallRequest {
+    fn new(func: Box<CallFunction>, num_of_cpus: usize) -> Arc<Self> {
+        Arc::new(Self {
+            func,
+            num_of_remaining_cpus: AtomicUsize::new(num_of_cpus),
+        })
+    }
+
+    fn run(&self) {
+        (self.func)();
+        self.num_of_remaining_cpus.fetch_sub(1, Ordering::Release);
+    }
+
+    fn is_completed(&self) -> bool {
+        self.num_of_remaining_cpus.load(Ordering::Acquire) == 0
+    }
+}
+
+impl CallQueue {
+    /// requestがまだ実行されていなければキューから取り除き、取り除けたかを返す
+    fn cancel(&self, request: &Arc<CallRequest>) -> bool {
+        let mut requests = self.requests.lock();
+        if let Some(index) = requests.iter().position(|r| Arc::ptr_eq(r, request)) {
+            requests.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl CallHandle {
+    /// 全ての対象のCPUで実行が終わるまで待つ
+    pub fn wait(&self) -> Result<(), CallError> {
+        wait_for_completion(&self.request, &self.queues)
+    }
+}
+
+/// このCPUの呼び出し用のキューを作成し、割り込みハンドラを設定する
+///
+/// 各CPUで、割り込みを許可する前に一度だけ呼び出してください。
+pub fn init_cpu_call() {
+    set_interrupt_handler(CALL_FUNCTION_VECTOR, call_function_interrupt_handler);
+    let queue = Box::leak(Box::new(CallQueue {
+        apic_id: get_apic_id(),
+        requests: SpinLock::new(VecDeque::new()),
+    }));
+    get_per_cpu_data().call_queue = Some(queue);
+    CALL_QUEUES.lock().push(queue);
+}
+
+/// init_cpu_callを呼び出したCPUの数
+pub fn get_num_of_online_cpus() -> usize {
+    CALL_QUEUES.lock().len()
+}
+
+fn find_queue(apic_id: u32) -> Option<&'static CallQueue> {
+    CALL_QUEUES
+        .lock()
+        .iter()
+        .find(|q| q.apic_id == apic_id)
+        .copied()
+}
+
+/// このCPUのキューに積まれた要求を全て実行する
+fn handle_call_queue() {
+    let queue = if let Some(queue) = get_per_cpu_data().call_queue {
+        queue
+    } else {
+        return;
+    };
+    loop {
+        /* 実行中はロックを解放しておく */
+        let request = queue.requests.lock().pop_front();
+        if let Some(request) = request {
+            request.run();
+        } else {
+            break;
+        }
+    }
+}
+
+fn get_timeout() -> u64 {
+    read_tsc().saturating_add(unsafe { TSC.get_timeout_count(CALL_TIMEOUT_MS) })
+}
+
+/// queuesに積んだrequestの実行が全て終わるまで待つ
+///
+/// タイムアウトした場合はまだ実行を始めていないCPUの分を取り消し、実行中の分が終わるのを待ってから
+/// CallError::Timeoutを返します。
+fn wait_for_completion(
+    request: &Arc<CallRequest>,
+    queues: &[&'static CallQueue],
+) -> Result<(), CallError> {
+    let timeout = get_timeout();
+    let mut result = Ok(());
+    while !request.is_completed() {
+        handle_call_queue();
+        if result.is_ok() && read_tsc() >= timeout {
+            let num_of_canceled = queues.iter().filter(|q| q.cancel(request)).count();
+            if num_of_canceled != 0 {
+                request
+                    .num_of_remaining_cpus
+                    .fetch_sub(num_of_canceled, Ordering::Release);
+                result = Err(CallError::Timeout);
+            }
+        }
+        core::hint::spin_loop();
+    }
+    result
+}
+
+fn post_request(queue: &CallQueue, request: &Arc<CallRequest>) {
+    queue.requests.lock().push_back(request.clone());
+    send_interrupt_command(
+        queue.apic_id,
+        0b000, /* Fixed */
+        0,
+        1,
+        CALL_FUNCTION_VECTOR,
+    );
+}
+
+/// requestをこのCPUを含むqueuesの全CPUで実行する(このCPUでは直接実行する)
+fn run_request(queues: &[&'static CallQueue], request: &Arc<CallRequest>) {
+    let apic_id = get_apic_id();
+    for queue in queues.iter().filter(|q| q.apic_id != apic_id) {
+        post_request(queue, request);
+    }
+    if queues.iter().any(|q| q.apic_id == apic_id) {
+        request.run();
+    }
+}
+
+/// 完了を待つ呼び出し用に、funcの寿命を'staticとして扱う
+///
+/// 呼び出し側はfuncを含む要求をrelease_requestで後始末し、
+/// 他のCPUから参照されなくなるまで'aの間の値を破棄しないようにする必要があります。
+unsafe fn erase_lifetime<'a>(func: Box<dyn Fn() + Send + Sync + 'a>) -> Box<CallFunction> {
+    // SAFETY: 寿命以外は同じ型のBoxなので、メモリ上の表現(データとvtableのポインタ)は変わらない。
+    // funcが'aより長く使われないことは呼び出し側(run_on_cpu・run_on_all_cpus)がrelease_requestで保証する:
+    // 全てのCPUの実行が終わるか取り消されるまで待ち、その後も他のCPUが要求を手放すまでは戻らない。
+    // 手放されなかった場合は要求を解放しないので、funcが他のCPUで'aの後に破棄されることもない。
+    core::mem::transmute(func)
+}
+
+/// 同期版の呼び出しの後始末(他のCPUがrequestを手放すまで待ってから解放する)
+fn release_request(
+    request: Arc<CallRequest>,
+    queues: &[&'static CallQueue],
+) -> Result<(), CallError> {
+    let result = wait_for_completion(&request, queues);
+    /* 実行を終えたCPUはすぐに手放すはずなので、待つのはCALL_TIMEOUT_MSまでにする */
+    let timeout = get_timeout();
+    while Arc::strong_count(&request) > 1 {
+        if read_tsc() >= timeout {
+            pr_warn!("A cross-CPU call request is still referenced, leak it");
+            /* 他のCPUで最後に手放された時にfuncが破棄されないようにする */
+            core::mem::forget(request);
+            return result;
+        }
+        core::hint::spin_loop();
+    }
+    result
+}
+
+/// apic_idのCPUでfuncを実行し、終わるまで待つ
+pub fn run_on_cpu<F: Fn() + Send + Sync>(apic_id: u32, func: F) -> Result<(), CallError> {
+    if apic_id == get_apic_id() {
+        func();
+        return Ok(());
+    }
+    let queue = find_queue(apic_id).ok_or(CallError::CpuNotFound)?;
+    let request = CallRequest::new(unsafe { erase_lifetime(Box::new(func)) }, 1);
+    post_request(queue, &request);
+    release_request(request, &[queue])
+}
+
+/// apic_idのCPUでfuncを実行するように依頼し、終わるのを待たずに戻る
+///
+/// apic_idがこのCPUの場合はその場で実行します。
+pub fn run_on_cpu_async<F: Fn() + Send + Sync + 'static>(
+    apic_id: u32,
+    func: F,
+) -> Result<CallHandle, CallError> {
+    let request = CallRequest::new(Box::new(func), 1);
+    let mut queues = Vec::new();
+    if apic_id == get_apic_id() {
+        request.run();
+    } else {
+        let queue = find_queue(apic_id).ok_or(CallError::CpuNotFound)?;
+        post_request(queue, &request);
+        queues.push(queue);
+    }
+    Ok(CallHandle { request, queues })
+}
+
+/// init_cpu_callを呼び出した全てのCPU(このCPUを含む)でfuncを実行し、終わるまで待つ
+pub fn run_on_all_cpus<F: Fn() + Send + Sync>(func: F) -> Result<(), CallError> {
+    let queues = CALL_QUEUES.lock().clone();
+    let request = CallRequest::new(unsafe { erase_lifetime(Box::new(func)) }, queues.len());
+    run_request(&queues, &request);
+    release_request(request, &queues)
+}
+
+/// 全てのCPUで関数を呼び出せるかを確かめ、呼び出せたCPUの数を返す
+///
+/// 起動時の確認用です。全てのCPUがinit_cpu_callを呼び出した後に呼び出してください。
+pub fn check_cpu_call() -> Result<usize, CallError> {
+    let queues = CALL_QUEUES.lock().clone();
+    for queue in queues.iter() {
+        let apic_id = AtomicU32::new(u32::MAX);
+        run_on_cpu(queue.apic_id, || {
+            apic_id.store(get_apic_id(), Ordering::Relaxed)
+        })?;
+        assert_eq!(apic_id.load(Ordering::Relaxed), queue.apic_id);
+    }
+    let num_of_cpus = AtomicUsize::new(0);
+    run_on_all_cpus(|| {
+        num_of_cpus.fetch_add(1, Ordering::Relaxed);
+    })?;
+    Ok(num_of_cpus.load(Ordering::Relaxed))
+}
+
+fn call_function_interrupt_handler(_: &ExceptionFrame) {
+    handle_call_queue();
+}
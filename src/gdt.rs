@@ -0,0 +1,76 @@
+//! GDT管理用モジュール
+//!
+//! BSPはboot.sのGDTとTSSをそのまま使います。
+//! ビジー状態のTSS記述子は他のCPUから読み込めないため、APはboot.sのGDTをコピーして
+//! 自分用のTSS記述子を書き込んだGDTを読み込みます。セグメントセレクタの値は全CPUで同じです。
+
+use super::tss::TaskStateSegmentWithIoMap;
+
+use alloc::boxed::Box;
+use core::arch::asm;
+
+const NUM_OF_GDT_ENTRIES: usize = 6;
+/// TSS記述子の位置(boot.sのtss_descriptorと同じ、2エントリ使う)
+const TSS_DESCRIPTOR_INDEX: usize = 4;
+/// 64bit TSS(非ビジー)
+const TSS_TYPE_AVAILABLE: u64 = 0b1001;
+const DESCRIPTOR_PRESENT: u64 = 1 << 47;
+
+/// boot.sのGDTと同じ並びのGDT
+#[repr(C, align(8))]
+pub struct GlobalDescriptorTable {
+    entries: [u64; NUM_OF_GDT_ENTRIES],
+}
+
+#[repr(C, packed)]
+struct GdtRegister {
+    limit: u16,
+    base: u64,
+}
+
+impl GlobalDescriptorTable {
+    /// TSS記述子をtssを指すように書き換える
+    fn set_tss(&mut self, tss: &TaskStateSegmentWithIoMap) {
+        let base = tss as *const _ as u64;
+        /* limitは最後のbyteのオフセット */
+        let limit = (core::mem::size_of_val(tss) - 1) as u64;
+        self.entries[TSS_DESCRIPTOR_INDEX] = (limit & 0xffff)
+            | ((base & 0xff_ffff) << 16)
+            | (TSS_TYPE_AVAILABLE << 40)
+            | DESCRIPTOR_PRESENT
+            | (((limit >> 16) & 0xf) << 48)
+            | (((base >> 24) & 0xff) << 56);
+        self.entries[TSS_DESCRIPTOR_INDEX + 1] = base >> 32;
+    }
+
+    /// このGDTを読み込み、TSS記述子をタスクレジスタに設定する
+    ///
+    /// 各CPUで一度だけ呼び出してください。
+    pub fn load(&'static self) {
+        let gdtr = GdtRegister {
+            limit: (core::mem::size_of_val(&self.entries) - 1) as u16,
+            base: self.entries.as_ptr() as u64,
+        };
+        unsafe {
+            asm!("lgdt [{}]", in(reg) &gdtr, options(readonly, nostack));
+            asm!("ltr {0:x}", in(reg) (TSS_DESCRIPTOR_INDEX * 8) as u16, options(nomem, nostack));
+        }
+    }
+}
+
+/// boot.sで作成し、BSPが読み込んでいるGDT
+pub fn get_boot_gdt() -> &'static GlobalDescriptorTable {
+    extern "C" {
+        static gdt: GlobalDescriptorTable;
+    }
+    unsafe { &gdt }
+}
+
+/// boot.sのGDTをコピーし、TSS記述子をtssに置き換えたGDTを作成する
+pub fn create_gdt(tss: &TaskStateSegmentWithIoMap) -> &'static GlobalDescriptorTable {
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable {
+        entries: get_boot_gdt().entries,
+    }));
+    gdt.set_tss(tss);
+    gdt
+}
@@ -0,0 +1,91 @@
+//! カーネルヒープ
+//!
+//! allocクレート(Vec, Box, BTreeMap, Arcなど)を使えるようにするための#[global_allocator]です。
+//! 2048byte以下の確保は2の累乗ごとのサイズクラスに分け、MemoryManagerから借りたページを
+//! 切り分けて貸し出します。それより大きい確保はMemoryManagerから直接ページ単位で確保します。
+//! サイズクラス用に借りたページは返却しません。
+//! MemoryManagerが返す物理アドレスは、ダイレクトマップ上の仮想アドレスに変換して貸し出します。
+
+use super::memory::PAGE_SIZE;
+use super::paging::{phys_to_virt, virt_to_phys};
+use super::spin_lock::SpinLock;
+use super::MEMORY_MANAGER;
+
+use core::alloc::{GlobalAlloc, Layout};
+
+/// 最小のサイズクラス(16byte)のシフト数
+const MIN_CLASS_SHIFT: usize = 4;
+/// 最大のサイズクラス(2048byte)のシフト数
+const MAX_CLASS_SHIFT: usize = 11;
+const NUM_OF_CLASSES: usize = MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1;
+
+pub struct KernelHeap {
+    /// 各サイズクラスの空きブロックの連結リストの先頭(0は空)
+    free_lists: SpinLock<[usize; NUM_OF_CLASSES]>,
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+impl KernelHeap {
+    const fn new() -> Self {
+        Self {
+            free_lists: SpinLock::new([0; NUM_OF_CLASSES]),
+        }
+    }
+
+    /// サイズクラスの番号を返す。大きすぎる場合はNone
+    fn get_class(layout: &Layout) -> Option<usize> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(1 << MIN_CLASS_SHIFT)
+            .next_power_of_two();
+        let shift = size.trailing_zeros() as usize;
+        if shift > MAX_CLASS_SHIFT {
+            None
+        } else {
+            Some(shift - MIN_CLASS_SHIFT)
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let class = if let Some(class) = Self::get_class(&layout) {
+            class
+        } else {
+            return MEMORY_MANAGER
+                .alloc_with_align(layout.size(), layout.align())
+                .map_or(0, phys_to_virt) as *mut u8;
+        };
+        let block_size = 1 << (class + MIN_CLASS_SHIFT);
+
+        let mut free_lists = self.free_lists.lock();
+        if free_lists[class] == 0 {
+            /* ページを借りてブロックに切り分ける */
+            let page = if let Some(page) = MEMORY_MANAGER.alloc_frames(0) {
+                phys_to_virt(page)
+            } else {
+                return core::ptr::null_mut();
+            };
+            for block in (page..(page + PAGE_SIZE)).step_by(block_size).rev() {
+                *(block as *mut usize) = free_lists[class];
+                free_lists[class] = block;
+            }
+        }
+        let block = free_lists[class];
+        free_lists[class] = *(block as *const usize);
+        block as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = Self::get_class(&layout) {
+            let mut free_lists = self.free_lists.lock();
+            *(ptr as *mut usize) = free_lists[class];
+            free_lists[class] = ptr as usize;
+        } else {
+            MEMORY_MANAGER.free(virt_to_phys(ptr as usize), layout.size());
+        }
+    }
+}
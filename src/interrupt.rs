@@ -0,0 +1,303 @@
+//! 割り込み・例外処理用モジュール
+//!
+//! BSPとAPで共有するIDTを管理します。
+//! CPU例外(0~31番)はinterrupt.sの入口で汎用レジスタを保存してからexception_handlerを呼び出し、
+//! レジスタの内容を表示してpanicします。
+//! #DF・NMI・#MCはenable_istを呼び出した後はTSSのISTに設定したスタックで処理します。
+//! NMIは診断用として、発生したCPUとレジスタの内容を表示して処理を続けます。
+//! 割り込み(32~255番)はinterrupt_handlerから登録されたハンドラを呼び出し、Local APICにEOIを送ります。
+
+use super::local_apic::{get_apic_id, send_eoi, SPURIOUS_INTERRUPT_VECTOR};
+use super::spin_lock::SpinLock;
+use super::stack::check_stack_overflow;
+use super::tss::{IST_DOUBLE_FAULT, IST_MACHINE_CHECK, IST_NMI};
+
+use core::arch::asm;
+
+const NUM_OF_IDT_ENTRIES: usize = 256;
+const NUM_OF_EXCEPTIONS: usize = 32;
+/// alloc_interrupt_vectorで割り当てるベクタの範囲(0xE0以降はLocal APICなどの固定の用途に使う)
+const FIRST_DYNAMIC_VECTOR: u8 = 0x20;
+const LAST_DYNAMIC_VECTOR: u8 = 0xDF;
+
+const NMI_VECTOR: u64 = 2;
+const DOUBLE_FAULT_VECTOR: u64 = 8;
+const PAGE_FAULT_VECTOR: u64 = 14;
+const MACHINE_CHECK_VECTOR: u64 = 18;
+
+/// 割り込みゲート(ハンドラの実行中は割り込み禁止)、DPL: 0、P: 1
+const GATE_TYPE_INTERRUPT: u8 = 0x8E;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GateDescriptor {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attributes: u8,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+#[repr(C, packed)]
+struct IdtRegister {
+    limit: u16,
+    base: u64,
+}
+
+/// interrupt.sが例外・割り込み発生時に保存するレジスタ
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// エラーコードのない例外と割り込みでは0
+    pub error_code: u64,
+    /* ここからはCPUが積んだもの */
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// 割り込みハンドラ(割り込み禁止の状態で呼ばれます)
+pub type InterruptHandler = fn(&ExceptionFrame);
+
+static mut IDT: [GateDescriptor; NUM_OF_IDT_ENTRIES] = [GateDescriptor::EMPTY; NUM_OF_IDT_ENTRIES];
+static INTERRUPT_HANDLERS: SpinLock<[Option<InterruptHandler>; NUM_OF_IDT_ENTRIES]> =
+    SpinLock::new([None; NUM_OF_IDT_ENTRIES]);
+
+const EXCEPTION_NAMES: [&str; NUM_OF_EXCEPTIONS] = [
+    "Divide Error",
+    "Debug Exception",
+    "NMI Interrupt",
+    "Breakpoint",
+    "Overflow",
+    "BOUND Range Exceeded",
+    "Invalid Opcode",
+    "Device Not Available",
+    "Double Fault",
+    "Coprocessor Segment Overrun",
+    "Invalid TSS",
+    "Segment Not Present",
+    "Stack-Segment Fault",
+    "General Protection",
+    "Page Fault",
+    "Reserved",
+    "x87 FPU Floating-Point Error",
+    "Alignment Check",
+    "Machine Check",
+    "SIMD Floating-Point Exception",
+    "Virtualization Exception",
+    "Control Protection Exception",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Hypervisor Injection Exception",
+    "VMM Communication Exception",
+    "Security Exception",
+    "Reserved",
+];
+
+impl GateDescriptor {
+    const EMPTY: Self = Self {
+        offset_low: 0,
+        selector: 0,
+        ist: 0,
+        type_attributes: 0,
+        offset_middle: 0,
+        offset_high: 0,
+        reserved: 0,
+    };
+
+    const fn new(handler_address: usize, selector: u16, ist: u8) -> Self {
+        Self {
+            offset_low: (handler_address & 0xffff) as u16,
+            selector,
+            ist,
+            type_attributes: GATE_TYPE_INTERRUPT,
+            offset_middle: ((handler_address >> 16) & 0xffff) as u16,
+            offset_high: (handler_address >> 32) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+fn get_code_segment() -> u16 {
+    let cs: u16;
+    unsafe { asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack)) };
+    cs
+}
+
+fn get_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack)) };
+    cr2
+}
+
+/// CPU例外と割り込みの入口を登録したIDTを作成し、読み込む
+///
+/// BSPで一度だけ呼び出してください。
+pub fn init_idt() {
+    extern "C" {
+        /* interrupt.s */
+        static exception_entry_table: [usize; NUM_OF_EXCEPTIONS];
+        static interrupt_entry_table: [usize; NUM_OF_IDT_ENTRIES - NUM_OF_EXCEPTIONS];
+    }
+    let selector = get_code_segment();
+    for (vector, entry) in unsafe { exception_entry_table.iter() }
+        .chain(unsafe { interrupt_entry_table.iter() })
+        .enumerate()
+    {
+        unsafe { IDT[vector] = GateDescriptor::new(*entry, selector, 0) };
+    }
+    load_idt();
+}
+
+/// vector番の割り込みのハンドラを設定する(既に設定されている場合は置き換える)
+pub fn set_interrupt_handler(vector: u8, handler: InterruptHandler) {
+    assert!(vector as usize >= NUM_OF_EXCEPTIONS);
+    INTERRUPT_HANDLERS.lock()[vector as usize] = Some(handler);
+}
+
+/// 使われていないベクタを探してhandlerを設定し、そのベクタを返す
+pub fn alloc_interrupt_vector(handler: InterruptHandler) -> Option<u8> {
+    let mut handlers = INTERRUPT_HANDLERS.lock();
+    let vector =
+        (FIRST_DYNAMIC_VECTOR..=LAST_DYNAMIC_VECTOR).find(|v| handlers[*v as usize].is_none())?;
+    handlers[vector as usize] = Some(handler);
+    Some(vector)
+}
+
+/// このCPUの割り込みを許可する
+pub fn enable_interrupt() {
+    unsafe { asm!("sti", options(nomem, nostack)) };
+}
+
+/// #DF・NMI・#MCをISTのスタックで処理するようにIDTを変更する
+///
+/// IDTは全CPUで共有しているため、ISTを設定したTSSを読み込んでから呼び出してください。
+pub fn enable_ist() {
+    for (vector, ist) in [
+        (DOUBLE_FAULT_VECTOR, IST_DOUBLE_FAULT),
+        (NMI_VECTOR, IST_NMI),
+        (MACHINE_CHECK_VECTOR, IST_MACHINE_CHECK),
+    ] {
+        unsafe { IDT[vector as usize].ist = ist };
+    }
+}
+
+/// init_idtで作成したIDTを読み込む(APは起動時にTSSを読み込んでから呼び出してください)
+pub fn load_idt() {
+    let idtr = IdtRegister {
+        limit: (core::mem::size_of_val(unsafe { &IDT }) - 1) as u16,
+        base: unsafe { &IDT as *const _ as u64 },
+    };
+    unsafe { asm!("lidt [{}]", in(reg) &idtr, options(readonly, nostack)) };
+}
+
+/// System Control Port B(NMIの原因のうち、6bit目はIOCHK、7bit目はメモリのパリティエラー)
+fn get_nmi_reason() -> u8 {
+    let reason: u8;
+    unsafe { asm!("in al, 0x61", out("al") reason, options(nomem, nostack)) };
+    reason & 0xc0
+}
+
+fn print_registers(frame: &ExceptionFrame) {
+    println!(
+        "RIP: {:#018X} RSP: {:#018X} RFLAGS: {:#018X}",
+        frame.rip, frame.rsp, frame.rflags
+    );
+    println!("CS:  {:#06X} SS: {:#06X}", frame.cs, frame.ss);
+    println!(
+        "RAX: {:#018X} RBX: {:#018X} RCX: {:#018X} RDX: {:#018X}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    );
+    println!(
+        "RSI: {:#018X} RDI: {:#018X} RBP: {:#018X}",
+        frame.rsi, frame.rdi, frame.rbp
+    );
+    println!(
+        "R8:  {:#018X} R9:  {:#018X} R10: {:#018X} R11: {:#018X}",
+        frame.r8, frame.r9, frame.r10, frame.r11
+    );
+    println!(
+        "R12: {:#018X} R13: {:#018X} R14: {:#018X} R15: {:#018X}",
+        frame.r12, frame.r13, frame.r14, frame.r15
+    );
+}
+
+/// NMIの発生したCPUとレジスタの内容を表示する(panicせずに処理を続ける)
+fn nmi_handler(frame: &ExceptionFrame) {
+    println!(
+        "\n!!!! NMI on CPU(APIC ID: {}) (Reason: {:#X}) !!!!",
+        get_apic_id(),
+        get_nmi_reason()
+    );
+    print_registers(frame);
+}
+
+#[no_mangle]
+extern "C" fn exception_handler(frame: &ExceptionFrame) {
+    if frame.vector == NMI_VECTOR {
+        nmi_handler(frame);
+        return;
+    }
+    let name = EXCEPTION_NAMES
+        .get(frame.vector as usize)
+        .unwrap_or(&"Unknown");
+    let apic_id = get_apic_id();
+
+    println!(
+        "\n!!!! Exception: {} (Vector: {}, Error Code: {:#X}) on CPU(APIC ID: {}) !!!!",
+        name, frame.vector, frame.error_code, apic_id
+    );
+    let cr2 = get_cr2();
+    if frame.vector == PAGE_FAULT_VECTOR || frame.vector == DOUBLE_FAULT_VECTOR {
+        println!("CR2: {:#018X}", cr2);
+    }
+    print_registers(frame);
+
+    if frame.vector == PAGE_FAULT_VECTOR || frame.vector == DOUBLE_FAULT_VECTOR {
+        check_stack_overflow(cr2 as usize);
+    }
+    panic!("{} on CPU(APIC ID: {})", name, apic_id);
+}
+
+#[no_mangle]
+extern "C" fn interrupt_handler(frame: &ExceptionFrame) {
+    /* スプリアス割り込みにはEOIを送らない */
+    if frame.vector == SPURIOUS_INTERRUPT_VECTOR as u64 {
+        return;
+    }
+    /* ハンドラの実行中はロックを解放しておく */
+    let handler = INTERRUPT_HANDLERS.lock()[frame.vector as usize];
+    if let Some(handler) = handler {
+        handler(frame);
+    } else {
+        pr_warn!(
+            "Unhandled interrupt (Vector: {}) on CPU(APIC ID: {})",
+            frame.vector,
+            get_apic_id()
+        );
+    }
+    send_eoi();
+}
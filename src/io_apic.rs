@@ -0,0 +1,249 @@
+//! I/O APIC
+//!
+//! デバイスからの割り込み(GSI: Global System Interrupt)を各CPUのLocal APICに届ける割り込みコントローラです。
+//! MADTのI/O APIC(タイプ1)とInterrupt Source Override(タイプ2)をもとに、
+//! ISAの割り込み番号(IRQ)からGSIへの変換と、リダイレクションテーブルの設定を行います。
+//! 8259 PICはboot.sで全てマスクしているので、レガシーな割り込みもI/O APIC経由で受け取ります。
+
+use super::interrupt::{alloc_interrupt_vector, InterruptHandler};
+use super::spin_lock::SpinLock;
+use super::PAGE_MANAGER;
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// I/O APICのレジスタ領域の大きさ
+const IO_APIC_SIZE: usize = 0x20;
+const IO_REGISTER_SELECT: usize = 0x00;
+const IO_WINDOW: usize = 0x10;
+const IO_APIC_VERSION_REGISTER: u32 = 0x01;
+const IO_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_POLARITY_LOW: u32 = 1 << 13;
+const REDIRECTION_TRIGGER_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+/// ISAの割り込み番号の数
+const NUM_OF_LEGACY_IRQS: usize = 16;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum IoApicError {
+    /// どのI/O APICも担当していないGSI
+    InvalidGsi,
+    /// I/O APICで指定できない宛先(xAPICの物理宛先は8bitまで)
+    InvalidDestination,
+    /// 空いている割り込みベクタがない
+    VectorNotAvailable,
+}
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    id: u8,
+    /// レジスタの仮想アドレス
+    base_address: usize,
+    gsi_base: u32,
+    num_of_entries: u32,
+}
+
+#[derive(Clone, Copy)]
+struct InterruptSourceOverride {
+    gsi: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+}
+
+pub struct IoApicManager {
+    io_apics: [Option<IoApic>; Self::MAX_NUM_OF_IO_APICS],
+    num_of_io_apics: usize,
+    /// IRQごとのInterrupt Source Override(なければIRQ = GSI、エッジトリガ、アクティブハイ)
+    overrides: [Option<InterruptSourceOverride>; NUM_OF_LEGACY_IRQS],
+    /// IOREGSELとIOWINの組を使う間のロック
+    lock: SpinLock<()>,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            write_volatile(
+                (self.base_address + IO_REGISTER_SELECT) as *mut u32,
+                register,
+            );
+            read_volatile((self.base_address + IO_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            write_volatile(
+                (self.base_address + IO_REGISTER_SELECT) as *mut u32,
+                register,
+            );
+            write_volatile((self.base_address + IO_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn is_responsible_for(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.num_of_entries
+    }
+
+    /// GSIに対応するリダイレクションテーブルの下位32bitのレジスタ番号
+    fn get_redirection_register(&self, gsi: u32) -> u32 {
+        IO_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2
+    }
+}
+
+impl IoApicManager {
+    pub const MAX_NUM_OF_IO_APICS: usize = 16;
+
+    pub const fn const_new() -> Self {
+        Self {
+            io_apics: [None; Self::MAX_NUM_OF_IO_APICS],
+            num_of_io_apics: 0,
+            overrides: [None; NUM_OF_LEGACY_IRQS],
+            lock: SpinLock::new(()),
+        }
+    }
+
+    /// MADTのI/O APIC(タイプ1)を追加し、全てのリダイレクションエントリをマスクする
+    pub fn add_io_apic(&mut self, id: u8, physical_address: usize, gsi_base: u32) {
+        if self.num_of_io_apics >= Self::MAX_NUM_OF_IO_APICS {
+            pr_warn!("Too many I/O APICs, ignore I/O APIC(ID: {})", id);
+            return;
+        }
+        let base_address = match unsafe { PAGE_MANAGER.map_mmio(physical_address, IO_APIC_SIZE) } {
+            Ok(a) => a,
+            Err(e) => {
+                pr_err!("Cannot map I/O APIC(ID: {}): {:?}", id, e);
+                return;
+            }
+        };
+        let mut io_apic = IoApic {
+            id,
+            base_address,
+            gsi_base,
+            num_of_entries: 0,
+        };
+        io_apic.num_of_entries = ((io_apic.read(IO_APIC_VERSION_REGISTER) >> 16) & 0xff) + 1;
+        for gsi in gsi_base..(gsi_base + io_apic.num_of_entries) {
+            io_apic.write(io_apic.get_redirection_register(gsi), REDIRECTION_MASKED);
+        }
+        pr_info!(
+            "I/O APIC(ID: {}): GSI {} - {}",
+            io_apic.id,
+            gsi_base,
+            gsi_base + io_apic.num_of_entries - 1
+        );
+        self.io_apics[self.num_of_io_apics] = Some(io_apic);
+        self.num_of_io_apics += 1;
+    }
+
+    /// MADTのInterrupt Source Override(タイプ2)を追加する
+    pub fn add_interrupt_source_override(&mut self, irq: u8, gsi: u32, flags: u16) {
+        if irq as usize >= NUM_OF_LEGACY_IRQS {
+            return;
+        }
+        let (polarity, trigger_mode) = parse_mps_inti_flags(flags);
+        self.overrides[irq as usize] = Some(InterruptSourceOverride {
+            gsi,
+            polarity,
+            trigger_mode,
+        });
+    }
+
+    fn find_io_apic(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics[..self.num_of_io_apics]
+            .iter()
+            .filter_map(|i| i.as_ref())
+            .find(|i| i.is_responsible_for(gsi))
+    }
+
+    /// ISAの割り込み番号に対応するGSIと極性、トリガモードを返す
+    pub fn get_legacy_irq_setting(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        if let Some(o) = self.overrides.get(irq as usize).and_then(|o| *o) {
+            (o.gsi, o.polarity, o.trigger_mode)
+        } else {
+            (irq as u32, Polarity::ActiveHigh, TriggerMode::Edge)
+        }
+    }
+
+    /// gsiの割り込みをdestination_apic_idのCPUのvector番の割り込みとして届けるように設定する
+    pub fn set_redirection(
+        &self,
+        gsi: u32,
+        vector: u8,
+        destination_apic_id: u32,
+        polarity: Polarity,
+        trigger_mode: TriggerMode,
+    ) -> Result<(), IoApicError> {
+        let io_apic = self.find_io_apic(gsi).ok_or(IoApicError::InvalidGsi)?;
+        if destination_apic_id > 0xff {
+            return Err(IoApicError::InvalidDestination);
+        }
+        /* Fixedモード、物理宛先 */
+        let mut low = vector as u32;
+        if polarity == Polarity::ActiveLow {
+            low |= REDIRECTION_POLARITY_LOW;
+        }
+        if trigger_mode == TriggerMode::Level {
+            low |= REDIRECTION_TRIGGER_LEVEL;
+        }
+        let high = destination_apic_id << 24;
+        let register = io_apic.get_redirection_register(gsi);
+
+        let _lock = self.lock.lock();
+        /* 設定中に割り込みが届かないよう、マスクしてから書き換える */
+        io_apic.write(register, REDIRECTION_MASKED);
+        io_apic.write(register + 1, high);
+        io_apic.write(register, low);
+        Ok(())
+    }
+
+    /// ISAの割り込み番号irqにhandlerを割り当て、destination_apic_idのCPUに届くように設定する
+    ///
+    /// 割り当てた割り込みベクタを返します。
+    pub fn request_legacy_irq(
+        &self,
+        irq: u8,
+        handler: InterruptHandler,
+        destination_apic_id: u32,
+    ) -> Result<u8, IoApicError> {
+        let (gsi, polarity, trigger_mode) = self.get_legacy_irq_setting(irq);
+        if self.find_io_apic(gsi).is_none() {
+            return Err(IoApicError::InvalidGsi);
+        }
+        if destination_apic_id > 0xff {
+            return Err(IoApicError::InvalidDestination);
+        }
+        let vector = alloc_interrupt_vector(handler).ok_or(IoApicError::VectorNotAvailable)?;
+        self.set_redirection(gsi, vector, destination_apic_id, polarity, trigger_mode)?;
+        Ok(vector)
+    }
+}
+
+/// MADTのMPS INTI Flags(0~1bit: 極性、2~3bit: トリガモード)を解析する
+///
+/// 0(バスの規定に従う)はISAの既定値のアクティブハイ・エッジトリガとして扱います。
+pub fn parse_mps_inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = if (flags & 0b11) == 0b11 {
+        Polarity::ActiveLow
+    } else {
+        Polarity::ActiveHigh
+    };
+    let trigger_mode = if ((flags >> 2) & 0b11) == 0b11 {
+        TriggerMode::Level
+    } else {
+        TriggerMode::Edge
+    };
+    (polarity, trigger_mode)
+}
@@ -0,0 +1,39 @@
+//! PS/2キーボード
+//!
+//! ISAのIRQ1をI/O APIC経由で受け取り、届いたスキャンコードを表示します。
+//! スキャンコードから文字への変換は行っていません。
+
+use super::interrupt::ExceptionFrame;
+use super::io_apic::{IoApicError, IoApicManager};
+
+use core::arch::asm;
+
+const KEYBOARD_IRQ: u8 = 1;
+const KEYBOARD_DATA_PORT: u16 = 0x60;
+
+/// キーボードの割り込み(IRQ1)をdestination_apic_idのCPUに届くように設定し、割り当てたベクタを返す
+pub fn init_keyboard(
+    io_apic_manager: &IoApicManager,
+    destination_apic_id: u32,
+) -> Result<u8, IoApicError> {
+    let vector = io_apic_manager.request_legacy_irq(
+        KEYBOARD_IRQ,
+        keyboard_interrupt_handler,
+        destination_apic_id,
+    )?;
+    /* 出力バッファにデータが残っていると次の割り込みが発生しないため、読み捨てる */
+    read_data();
+    Ok(vector)
+}
+
+fn read_data() -> u8 {
+    let data: u8;
+    unsafe {
+        asm!("in al, dx", in("dx") KEYBOARD_DATA_PORT, out("al") data, options(nomem, nostack))
+    };
+    data
+}
+
+fn keyboard_interrupt_handler(_: &ExceptionFrame) {
+    pr_debug!("Keyboard: Scan code {:#X}", read_data());
+}
@@ -1,156 +1,241 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![feature(alloc_error_handler)]
 #![feature(let_chains)]
 #![feature(panic_info_message)]
 
+extern crate alloc;
+
 #[macro_use]
 mod print;
 mod acpi;
 mod acpi_pm_timer;
 mod ap;
+/* ホストで`cargo test`を実行する時は、ブート用のコード・ヒープ・パニックなどのハンドラを除く */
+#[cfg(not(test))]
 mod asm;
+mod boot_module;
+mod boot_option;
+mod cpu_call;
+mod gdt;
+#[cfg(not(test))]
+mod heap;
+mod interrupt;
+mod io_apic;
+mod keyboard;
 mod local_apic;
+mod local_apic_timer;
 mod memory;
+mod multiboot2;
+mod paging;
+mod parallel;
+mod spin_lock;
+mod stack;
+mod tlb;
+mod tsc;
+mod tss;
 
-use acpi::{get_acpi_pm_timer, get_apic_id_list, ApicIdList};
+use acpi::{
+    add_acpi_table_areas, add_io_apics, add_local_apic_nmis, get_acpi_pm_timer, get_apic_id_list,
+    get_local_apic_address, ApicIdList,
+};
 use acpi_pm_timer::AcpiPmTimer;
-use ap::init_ap;
-use memory::{MemoryManager, MultibootTagElfSections, MultibootTagMemoryMap};
+use ap::{get_per_cpu_data, init_ap, init_per_cpu_data_on_bsp};
+use boot_module::{BootModule, BootModuleList};
+use boot_option::BootOptions;
+use cpu_call::{check_cpu_call, init_cpu_call};
+use interrupt::{enable_interrupt, enable_ist, init_idt};
+use io_apic::IoApicManager;
+use keyboard::init_keyboard;
+use local_apic::{get_apic_id, init_local_apic, setup_local_apic, LocalApicNmiList};
+use local_apic_timer::{
+    check_one_shot, check_tsc_deadline, init_local_apic_timer, is_local_apic_timer_working,
+};
+use memory::{MemoryManager, ReservedAreaList};
+use multiboot2::{MultibootInformation, MultibootTag};
+use paging::{virt_to_phys, CacheMode, PageAttribute, PageManager};
+use parallel::check_parallel;
 use print::PRINT_MANAGER;
+use stack::{switch_stack, StackManager};
+use tlb::check_tlb_shootdown;
+use tsc::Tsc;
+use tss::set_ist_stacks;
 
 use core::arch::asm;
 use core::panic;
 
-#[repr(C)]
-struct MultibootTag {
-    s_type: u32,
-    size: u32,
-}
-
-#[repr(C)]
-#[allow(dead_code)]
-pub struct MultibootTagFrameBuffer {
-    s_type: u32,
-    size: u32,
-    frame_buffer_addr: u64,
-    frame_buffer_pitch: u32,
-    frame_buffer_width: u32,
-    frame_buffer_height: u32,
-    frame_buffer_bpp: u8,
-    frame_buffer_type: u8,
-    /* https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html 3.6.12 Framebuffer info */
-    reserved: u8,
-    /* color_info is ignored */
-}
-
-#[repr(C)]
-struct MultibootTagModule {
-    s_type: u32,
-    size: u32,
-    mod_start: u32,
-    mod_end: u32,
-    string: u8,
-}
-
 static mut MEMORY_MANAGER: MemoryManager = MemoryManager::const_new();
+static mut PAGE_MANAGER: PageManager = PageManager::const_new();
 static mut APIC_ID_LIST: ApicIdList = ApicIdList::const_new();
 static mut ACPI_PM_TIMER: AcpiPmTimer = AcpiPmTimer::const_new();
+static mut BOOT_OPTIONS: BootOptions = BootOptions::const_new();
+static mut BOOT_MODULE_LIST: BootModuleList = BootModuleList::const_new();
+static mut STACK_MANAGER: StackManager = StackManager::const_new();
+static mut TSC: Tsc = Tsc::const_new();
+static mut IO_APIC_MANAGER: IoApicManager = IoApicManager::const_new();
+static mut LOCAL_APIC_NMI_LIST: LocalApicNmiList = LocalApicNmiList::const_new();
 
 #[no_mangle]
 extern "C" fn boot_main(multiboot_info_address: usize) -> ! {
     init(multiboot_info_address);
+    /* boot.sのスタックからガードページ付きのスタックに切り替える */
+    let stack = unsafe { STACK_MANAGER.alloc_stack(0, BOOT_OPTIONS.stack_size) }
+        .expect("Cannot allocate the stack for BSP");
+    init_per_cpu_data_on_bsp();
+    /* #DF・NMI・#MCは壊れたスタックの上でも処理できるようにISTのスタックを使う */
+    set_ist_stacks(&mut get_per_cpu_data().get_tss().tss, 0);
+    enable_ist();
+    switch_stack(stack.stack_top, bsp_main)
+}
+
+extern "C" fn bsp_main() -> ! {
     println!("Setup application processors!!");
-    unsafe { init_ap(APIC_ID_LIST.clone(), &ACPI_PM_TIMER) };
+    unsafe { init_ap(APIC_ID_LIST.clone(), &ACPI_PM_TIMER, &BOOT_OPTIONS) };
+    setup_local_apic(unsafe { &LOCAL_APIC_NMI_LIST });
+    init_local_apic_timer(unsafe { &ACPI_PM_TIMER });
+    init_cpu_call();
+    unsafe { PAGE_MANAGER.activate() };
+    /* 下位のアドレスを空けるため、APの起動後は仮想アドレス = 物理アドレスのマップを解除する */
+    /* APのTLBもIPIで無効化するので、Local APICの設定後に行う */
+    #[cfg(feature = "higher_half")]
+    unsafe {
+        PAGE_MANAGER.remove_identity_map()
+    };
+    unsafe {
+        pr_info!(
+            "Memory: {}KiB used, {}KiB free / {}KiB",
+            MEMORY_MANAGER.get_used_memory_size() >> 10,
+            MEMORY_MANAGER.get_free_memory_size() >> 10,
+            MEMORY_MANAGER.get_total_memory_size() >> 10
+        )
+    };
+    match check_cpu_call() {
+        Ok(num_of_cpus) => pr_info!("Cross-CPU calls work on {} CPUs", num_of_cpus),
+        Err(e) => pr_err!("Cross-CPU calls failed: {:?}", e),
+    }
+    if !check_parallel() {
+        pr_err!("Parallel processing returned a wrong result");
+    }
+    if !check_tlb_shootdown() {
+        pr_err!("TLB shootdown did not reach all CPUs");
+    }
+    match init_keyboard(unsafe { &IO_APIC_MANAGER }, get_apic_id()) {
+        Ok(vector) => pr_debug!("Keyboard: Vector {:#X}", vector),
+        Err(e) => pr_warn!("Cannot set up the keyboard interrupt: {:?}", e),
+    }
     println!("Setup succeeded!!");
+    enable_interrupt();
+    if !is_local_apic_timer_working() {
+        pr_err!("Local APIC Timer interrupts are not delivered");
+    }
+    if !check_tsc_deadline() {
+        pr_err!("TSC-Deadline interrupts are not delivered alongside the tick");
+    }
+    if !check_one_shot() {
+        pr_err!("Local APIC Timer one-shot interrupt is not delivered");
+    }
     loop {
         unsafe { asm!("hlt") };
     }
 }
 
 fn init(multiboot_info_address: usize) {
-    if multiboot_info_address & 7 != 0 {
-        panic!("Invalid Multiboot information address");
-    }
-    let mut tag = multiboot_info_address + 8;
+    init_idt();
+    unsafe { PAGE_MANAGER = PageManager::new() };
 
-    let mut elf_info_address = 0usize;
-    let mut memory_map_info_address = 0usize;
-    let mut frame_buffer_info_address = 0usize;
-    let mut font_data_address = 0usize;
-    let mut font_data_size = 0usize;
+    let multiboot_info = match unsafe { MultibootInformation::new(multiboot_info_address) } {
+        Ok(info) => info,
+        Err(e) => panic!("Invalid Multiboot information: {}", e),
+    };
+
+    let mut command_line = "";
+    let mut elf_sections = None;
+    let mut memory_map = None;
+    let mut frame_buffer = None;
     let mut new_rsdp_address = 0usize;
     let mut old_rsdp_address = 0usize;
 
-    const TAG_TYPE_END: u32 = 0;
-    const TAG_TYPE_MODULE: u32 = 3;
-    const TAG_TYPE_MMAP: u32 = 6;
-    const TAG_TYPE_FRAMEBUFFER: u32 = 8;
-    const TAG_TYPE_ELF_SECTIONS: u32 = 9;
-    const TAG_TYPE_ACPI_OLD: u32 = 14;
-    const TAG_TYPE_ACPI_NEW: u32 = 15;
-
-    loop {
-        match unsafe { (*(tag as *const MultibootTag)).s_type } {
-            TAG_TYPE_END => {
-                break;
+    for tag in multiboot_info.tags() {
+        match tag {
+            MultibootTag::CommandLine(c) => {
+                command_line = c;
             }
-            TAG_TYPE_MMAP => {
-                memory_map_info_address = tag;
+            MultibootTag::MemoryMap(m) => {
+                memory_map = Some(m);
             }
-            TAG_TYPE_MODULE => {
-                let module_info = unsafe { &*(tag as *const MultibootTagModule) };
-                if core::str::from_utf8(unsafe {
-                    core::slice::from_raw_parts(
-                        &module_info.string,
-                        module_info.size as usize - 16 - 1, /*\0*/
-                    )
-                })
-                .unwrap_or("")
-                    == "font.pf2"
-                {
-                    font_data_address = module_info.mod_start as usize;
-                    font_data_size = module_info.mod_end as usize - font_data_address;
+            MultibootTag::Module(m) => {
+                let module =
+                    BootModule::new(m.string, m.start_address as usize, m.end_address as usize);
+                if !unsafe { BOOT_MODULE_LIST.add(module) } {
+                    pr_warn!("Cannot register the boot module: {}", m.string);
                 }
             }
-            TAG_TYPE_FRAMEBUFFER => {
-                frame_buffer_info_address = tag;
+            MultibootTag::FrameBuffer(f) => {
+                frame_buffer = Some(f);
             }
-            TAG_TYPE_ELF_SECTIONS => {
-                elf_info_address = tag;
+            MultibootTag::ElfSections(e) => {
+                elf_sections = Some(e);
             }
-            TAG_TYPE_ACPI_NEW => {
-                new_rsdp_address = tag + 8;
+            MultibootTag::AcpiNew(rsdp) => {
+                new_rsdp_address = virt_to_phys(rsdp.as_ptr() as usize);
             }
-            TAG_TYPE_ACPI_OLD => {
-                old_rsdp_address = tag + 8;
+            MultibootTag::AcpiOld(rsdp) => {
+                old_rsdp_address = virt_to_phys(rsdp.as_ptr() as usize);
             }
             _ => {}
         }
-        tag += (unsafe { (*(tag as *const MultibootTag)).size } as usize + 7) & !7;
-    }
-
-    if frame_buffer_info_address != 0 {
-        let frame_buffer_info =
-            unsafe { &*(frame_buffer_info_address as *const MultibootTagFrameBuffer) };
-        unsafe {
-            PRINT_MANAGER.init(
-                frame_buffer_info.frame_buffer_addr as usize,
-                frame_buffer_info.frame_buffer_width as usize,
-                frame_buffer_info.frame_buffer_height as usize,
-                frame_buffer_info.frame_buffer_bpp as u8,
-                font_data_address,
-                font_data_size,
+    }
+
+    let boot_options = BootOptions::parse(command_line);
+    unsafe {
+        PRINT_MANAGER.set_console(&boot_options.console);
+        PRINT_MANAGER.set_log_level(boot_options.log_level);
+    }
+
+    if let Some(frame_buffer_info) = frame_buffer
+        .as_ref()
+        .filter(|_| !boot_options.no_frame_buffer && boot_options.console.graphic)
+    {
+        let (font_data_address, font_data_size) = if let Some(font) =
+            unsafe { BOOT_MODULE_LIST.find("font.pf2") }.filter(|f| f.get_size() != 0)
+        {
+            (
+                unsafe { PAGE_MANAGER.ensure_direct_map(font.start_address, font.get_size()) }
+                    .unwrap_or(0),
+                font.get_size(),
             )
+        } else {
+            (0, 0)
         };
+        /* UC-にしておき、MTRRでWCが設定されていればそれを使う */
+        match unsafe {
+            PAGE_MANAGER.map_physical_memory(
+                frame_buffer_info.address as usize,
+                frame_buffer_info.pitch as usize * frame_buffer_info.height as usize,
+                PageAttribute::new(true, true, false, CacheMode::UncachedMinus),
+            )
+        } {
+            Ok(frame_buffer_address) => unsafe {
+                PRINT_MANAGER.init(
+                    frame_buffer_address,
+                    frame_buffer_info.width as usize,
+                    frame_buffer_info.height as usize,
+                    frame_buffer_info.bpp,
+                    font_data_address,
+                    font_data_size,
+                )
+            },
+            Err(e) => pr_warn!("Cannot map the frame buffer: {:?}", e),
+        }
     }
 
-    assert_ne!(elf_info_address, 0);
-    assert_ne!(memory_map_info_address, 0);
+    let elf_sections = elf_sections.expect("ELF sections tag is not found");
+    let memory_map = memory_map.expect("Memory map tag is not found");
 
-    unsafe {
-        MEMORY_MANAGER = MemoryManager::new(
-            &*(memory_map_info_address as *const MultibootTagMemoryMap),
-            &*(elf_info_address as *const MultibootTagElfSections),
-        );
+    if !command_line.is_empty() {
+        pr_info!("Command line: {}", command_line);
+    }
+    for tag in multiboot_info.tags() {
+        tag.print_info();
     }
 
     if new_rsdp_address == 0 && old_rsdp_address == 0 {
@@ -158,19 +243,83 @@ fn init(multiboot_info_address: usize) {
     }
 
     let rsdp_address = if new_rsdp_address != 0 {
-        println!("ACPI 2.0 or later");
+        pr_info!("ACPI 2.0 or later");
         new_rsdp_address
     } else {
-        println!("ACPI 1.0");
+        pr_info!("ACPI 1.0");
         old_rsdp_address
     };
 
+    /* ブートローダーやファームウェアが使用している領域を貸し出さないようにする */
+    let mut reserved_areas = ReservedAreaList::new();
+    reserved_areas.add(
+        multiboot_info.get_address(),
+        multiboot_info.get_total_size(),
+        "Multiboot information",
+    );
+    for module in unsafe { BOOT_MODULE_LIST.iter() } {
+        reserved_areas.add(module.start_address, module.get_size(), "Boot module");
+    }
+    add_acpi_table_areas(rsdp_address, &mut reserved_areas);
+    if let Some(frame_buffer_info) = &frame_buffer {
+        reserved_areas.add(
+            frame_buffer_info.address as usize,
+            frame_buffer_info.pitch as usize * frame_buffer_info.height as usize,
+            "Frame buffer",
+        );
+    }
+
+    unsafe {
+        MEMORY_MANAGER = MemoryManager::new(
+            &memory_map,
+            &elf_sections,
+            reserved_areas.as_slice(),
+            boot_options.memory_limit,
+        );
+        PAGE_MANAGER
+            .map_all_physical_memory(&memory_map)
+            .expect("Cannot map the physical memory");
+        PAGE_MANAGER
+            .protect_kernel_sections(&elf_sections)
+            .expect("Cannot change the attributes of the kernel sections");
+        BOOT_OPTIONS = boot_options;
+        MEMORY_MANAGER.print_memory_map(&memory_map, reserved_areas.as_slice());
+        for module in BOOT_MODULE_LIST.iter() {
+            pr_info!(
+                "Boot module: {} ({:#X} - {:#X}) {}",
+                module.name,
+                module.start_address,
+                module.end_address,
+                module.arguments
+            );
+        }
+    }
+
+    init_local_apic(get_local_apic_address(rsdp_address));
+    if unsafe { add_local_apic_nmis(rsdp_address, &mut LOCAL_APIC_NMI_LIST) }.is_none() {
+        pr_warn!("Cannot get Local APIC NMI entries");
+    }
+
     unsafe {
         APIC_ID_LIST = get_apic_id_list(rsdp_address).expect("Cannot get Local APIC ID List!");
         ACPI_PM_TIMER = get_acpi_pm_timer(rsdp_address).expect("Cannot get ACPI PM Timer!");
+        TSC = Tsc::new(&ACPI_PM_TIMER);
+        pr_info!(
+            "TSC: {} Hz (Invariant: {}, TSC-Deadline: {})",
+            TSC.get_frequency(),
+            TSC.is_invariant(),
+            TSC.is_deadline_supported()
+        );
+        if !TSC.is_invariant() {
+            pr_warn!("TSC is not invariant, Local APIC Timer uses the periodic mode");
+        }
+        if add_io_apics(rsdp_address, &mut IO_APIC_MANAGER).is_none() {
+            pr_warn!("Cannot get I/O APICs");
+        }
     }
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 #[no_mangle]
 pub fn panic(info: &panic::PanicInfo) -> ! {
@@ -182,3 +331,13 @@ pub fn panic(info: &panic::PanicInfo) -> ! {
         unsafe { asm!("hlt") };
     }
 }
+
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    panic!(
+        "Cannot allocate memory: size = {:#X}, align = {:#X}",
+        layout.size(),
+        layout.align()
+    );
+}
@@ -1,7 +1,312 @@
-pub fn get_apic_id() -> u8 {
-    (unsafe { (core::ptr::read_volatile((0xfee00020usize) as *const u32) >> 24) & 0xff }) as u8
+//! Local APIC
+//!
+//! 各CPUに一つずつある割り込みコントローラです。
+//! レジスタの物理アドレスはIA32_APIC_BASEから取得し、MADTの値(タイプ5のオーバーライドを含む)と
+//! 異なる場合は警告します(CPUが実際に使うのはIA32_APIC_BASEのアドレスのため)。
+//! CPUがx2APICに対応していれば、BSPでinit_local_apicを呼び出した時点で全CPUをx2APICモードで使います。
+//! xAPICモードではレジスタをMMIOで、x2APICモードではMSRで読み書きします。
+//! レジスタはxAPICのMMIOのオフセットで指定します(x2APICのMSRの番号は0x800 + オフセット / 16です)。
+//! LINT0/LINT1のNMIの配線はMADTのLocal APIC NMI(タイプ4・0xA)に従って各CPUで設定します。
+
+use super::interrupt::{set_interrupt_handler, ExceptionFrame};
+use super::io_apic::{parse_mps_inti_flags, Polarity, TriggerMode};
+use super::paging::phys_to_virt;
+use super::spin_lock::{restore_interrupt, save_and_disable_interrupt};
+use super::tsc::cpuid;
+use super::PAGE_MANAGER;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Local APICのレジスタ領域の大きさ
+pub const LOCAL_APIC_SIZE: usize = 0x1000;
+/// Local APICを有効にする際に設定するスプリアス割り込みのベクタ
+pub const SPURIOUS_INTERRUPT_VECTOR: u8 = 0xff;
+/// Local APICのエラー割り込みのベクタ
+pub const LOCAL_APIC_ERROR_VECTOR: u8 = 0xfe;
+
+/// init_local_apicを呼び出す前に使うアドレス(アーキテクチャの既定値)
+const DEFAULT_LOCAL_APIC_ADDRESS: usize = 0xfee00000;
+
+const APIC_ID_REGISTER: usize = 0x20;
+const TASK_PRIORITY_REGISTER: usize = 0x80;
+const EOI_REGISTER: usize = 0xb0;
+const SPURIOUS_INTERRUPT_VECTOR_REGISTER: usize = 0xf0;
+const ERROR_STATUS_REGISTER: usize = 0x280;
+const INTERRUPT_COMMAND_REGISTER: usize = 0x300;
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_TRIGGER_LEVEL: u32 = 1 << 15;
+const LVT_POLARITY_LOW: u32 = 1 << 13;
+const LVT_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APICのレジスタの物理アドレス
+static LOCAL_APIC_ADDRESS: AtomicUsize = AtomicUsize::new(DEFAULT_LOCAL_APIC_ADDRESS);
+static IS_X2APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+/// LVT(Local Vector Table)のエントリ
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Lvt {
+    Timer,
+    Lint0,
+    Lint1,
+    Error,
+}
+
+/// MADTのLocal APIC NMI(タイプ4)・Local x2APIC NMI(タイプ0xA)で指定されたNMIの配線
+#[derive(Clone, Copy)]
+struct LocalApicNmi {
+    /// 対象のCPU(Noneは全CPU)
+    apic_id: Option<u32>,
+    lint: Lvt,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+}
+
+pub struct LocalApicNmiList {
+    entries: [Option<LocalApicNmi>; Self::MAX_NUM_OF_ENTRIES],
+    num_of_entries: usize,
+}
+
+impl LocalApicNmiList {
+    const MAX_NUM_OF_ENTRIES: usize = 64;
+
+    pub const fn const_new() -> Self {
+        Self {
+            entries: [None; Self::MAX_NUM_OF_ENTRIES],
+            num_of_entries: 0,
+        }
+    }
+
+    /// apic_idのCPU(Noneは全CPU)のLINT[lint_number]がNMIにつながっていることを記録する
+    ///
+    /// flagsはMADTのMPS INTI Flagsです。
+    pub fn add(&mut self, apic_id: Option<u32>, lint_number: u8, flags: u16) {
+        let lint = match lint_number {
+            0 => Lvt::Lint0,
+            1 => Lvt::Lint1,
+            _ => return,
+        };
+        if self.num_of_entries >= Self::MAX_NUM_OF_ENTRIES {
+            pr_warn!("Too many Local APIC NMI entries");
+            return;
+        }
+        let (polarity, trigger_mode) = parse_mps_inti_flags(flags);
+        self.entries[self.num_of_entries] = Some(LocalApicNmi {
+            apic_id,
+            lint,
+            polarity,
+            trigger_mode,
+        });
+        self.num_of_entries += 1;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &LocalApicNmi> {
+        self.entries[..self.num_of_entries]
+            .iter()
+            .filter_map(|e| e.as_ref())
+    }
+}
+
+impl Lvt {
+    fn get_register(self) -> usize {
+        match self {
+            Self::Timer => 0x320,
+            Self::Lint0 => 0x350,
+            Self::Lint1 => 0x360,
+            Self::Error => 0x370,
+        }
+    }
+}
+
+fn read_msr(msr: u32) -> u64 {
+    let eax: u32;
+    let edx: u32;
+    unsafe { asm!("rdmsr", in("ecx") msr, out("eax") eax, out("edx") edx, options(nostack)) };
+    ((edx as u64) << 32) | eax as u64
+}
+
+fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack)
+        )
+    };
+}
+
+fn get_x2apic_msr(offset: usize) -> u32 {
+    X2APIC_MSR_BASE + (offset >> 4) as u32
+}
+
+/// このCPUのLocal APICをx2APICモードに切り替える
+fn switch_to_x2apic_mode() {
+    write_msr(
+        IA32_APIC_BASE_MSR,
+        read_msr(IA32_APIC_BASE_MSR) | APIC_BASE_GLOBAL_ENABLE | APIC_BASE_X2APIC_ENABLE,
+    );
+}
+
+/// Local APICのアドレスを調べてマップし、CPUが対応していればBSPのLocal APICをx2APICモードにする
+///
+/// madt_addressはMADTから得たアドレスです。BSPで一度だけ、Local APICを使う前に呼び出してください。
+/// ファームウェアが既にx2APICモードにしている場合もx2APICモードのまま使います。
+///
+/// MADTのアドレスはOSへの情報にすぎず、CPUがレジスタとして扱うのはIA32_APIC_BASEのアドレスなので、
+/// 両者が異なる場合もIA32_APIC_BASEを使います(MADTのアドレスにマップしてもLocal APICには届きません)。
+pub fn init_local_apic(madt_address: Option<usize>) {
+    let apic_base = read_msr(IA32_APIC_BASE_MSR);
+    let address = (apic_base & APIC_BASE_ADDRESS_MASK) as usize;
+    if let Some(madt_address) = madt_address {
+        if madt_address != address {
+            pr_warn!(
+                "Local APIC address in MADT ({:#X}) differs from IA32_APIC_BASE ({:#X})",
+                madt_address,
+                address
+            );
+        }
+    }
+    LOCAL_APIC_ADDRESS.store(address, Ordering::Relaxed);
+    unsafe { PAGE_MANAGER.map_mmio(address, LOCAL_APIC_SIZE) }.expect("Cannot map Local APIC");
+
+    let is_x2apic_supported = (cpuid(1, 0).2 & (1 << 21)) != 0;
+    if is_x2apic_supported || (apic_base & APIC_BASE_X2APIC_ENABLE) != 0 {
+        switch_to_x2apic_mode();
+        IS_X2APIC_MODE.store(true, Ordering::Relaxed);
+        pr_info!("x2APIC mode is enabled");
+    }
+}
+
+/// BSPがx2APICモードであれば、このCPUのLocal APICもx2APICモードにする
+///
+/// APの起動時にLocal APICを使う前に呼び出してください。
+pub fn init_x2apic_on_ap() {
+    if is_x2apic_mode() {
+        switch_to_x2apic_mode();
+    }
+}
+
+pub fn is_x2apic_mode() -> bool {
+    IS_X2APIC_MODE.load(Ordering::Relaxed)
+}
+
+pub fn read_register(offset: usize) -> u32 {
+    if is_x2apic_mode() {
+        read_msr(get_x2apic_msr(offset)) as u32
+    } else {
+        unsafe {
+            core::ptr::read_volatile(
+                (phys_to_virt(LOCAL_APIC_ADDRESS.load(Ordering::Relaxed)) + offset) as *const u32,
+            )
+        }
+    }
+}
+
+pub fn write_register(offset: usize, value: u32) {
+    if is_x2apic_mode() {
+        write_msr(get_x2apic_msr(offset), value as u64);
+    } else {
+        unsafe {
+            core::ptr::write_volatile(
+                (phys_to_virt(LOCAL_APIC_ADDRESS.load(Ordering::Relaxed)) + offset) as *mut u32,
+                value,
+            )
+        }
+    }
+}
+
+pub fn get_apic_id() -> u32 {
+    if is_x2apic_mode() {
+        read_register(APIC_ID_REGISTER)
+    } else {
+        (read_register(APIC_ID_REGISTER) >> 24) & 0xff
+    }
+}
+
+/// このCPUのLocal APICを有効にし、LVTを初期化する
+///
+/// LINT0は8259 PICを使わないのでマスクし、LINT1は一般的な配線に合わせてNMIにした上で、
+/// nmi_listにこのCPUの設定があればそれに従います。
+/// タイマーはマスクした状態になるので、local_apic_timerで設定してください。
+/// APではinit_x2apic_on_apの後に呼び出してください。
+pub fn setup_local_apic(nmi_list: &LocalApicNmiList) {
+    set_interrupt_handler(LOCAL_APIC_ERROR_VECTOR, error_interrupt_handler);
+    write_register(TASK_PRIORITY_REGISTER, 0);
+    write_register(
+        SPURIOUS_INTERRUPT_VECTOR_REGISTER,
+        APIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR as u32,
+    );
+    write_lvt(Lvt::Timer, LVT_MASKED);
+    write_lvt(Lvt::Lint0, LVT_MASKED);
+    set_lint_nmi(Lvt::Lint1, Polarity::ActiveHigh, TriggerMode::Edge);
+    let apic_id = get_apic_id();
+    for nmi in nmi_list
+        .iter()
+        .filter(|n| n.apic_id.is_none() || n.apic_id == Some(apic_id))
+    {
+        set_lint_nmi(nmi.lint, nmi.polarity, nmi.trigger_mode);
+    }
+    /* 有効にする前に記録されたエラーを消してからエラー割り込みを有効にする */
+    read_error_status();
+    write_lvt(Lvt::Error, LOCAL_APIC_ERROR_VECTOR as u32);
+    read_error_status();
+}
+
+pub fn write_lvt(lvt: Lvt, value: u32) {
+    write_register(lvt.get_register(), value);
+}
+
+/// LINT0/LINT1のピンをNMIとして届けるように設定する
+fn set_lint_nmi(lvt: Lvt, polarity: Polarity, trigger_mode: TriggerMode) {
+    assert!(lvt == Lvt::Lint0 || lvt == Lvt::Lint1);
+    let mut value = LVT_DELIVERY_MODE_NMI;
+    if polarity == Polarity::ActiveLow {
+        value |= LVT_POLARITY_LOW;
+    }
+    /* トリガモードはFixedのときのみ使われ、NMIでは無視される */
+    if trigger_mode == TriggerMode::Level {
+        value |= LVT_TRIGGER_LEVEL;
+    }
+    write_lvt(lvt, value);
+}
+
+/// Error Status Registerを読む
+///
+/// 書き込むと最新のエラーが読めるようになり、記録は消えます。
+pub fn read_error_status() -> u32 {
+    write_register(ERROR_STATUS_REGISTER, 0);
+    read_register(ERROR_STATUS_REGISTER)
 }
 
+/// 割り込みの処理が終わったことをLocal APICに通知する
+pub fn send_eoi() {
+    write_register(EOI_REGISTER, 0);
+}
+
+/// 前のIPIの送信が終わるまで待つ(x2APICモードには送信中の状態がない)
+fn wait_for_ipi_delivery() {
+    if is_x2apic_mode() {
+        return;
+    }
+    while (read_register(INTERRUPT_COMMAND_REGISTER) & ICR_DELIVERY_STATUS_PENDING) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// destinationにIPIを送る
+///
+/// xAPICモードではdestinationは8bitまでです。
 pub fn send_interrupt_command(
     destination: u32,
     delivery_mode: u8,
@@ -10,16 +315,32 @@ pub fn send_interrupt_command(
     vector: u8,
 ) {
     assert!(delivery_mode < 8);
-    let mut data: u64 = ((trigger_mode as u64) << 15)
-        | ((level as u64) << 14)
-        | ((delivery_mode as u64) << 8)
-        | (vector as u64);
-    assert!(destination <= 0xff);
-    data |= (destination as u64) << 56;
-    let high = (data >> 32) as u32;
-    let low = data as u32;
-    unsafe {
-        core::ptr::write_volatile((0xfee00000usize + (0x30 + 1) * 0x10) as *mut u32, high);
-        core::ptr::write_volatile((0xfee00000usize + (0x30) * 0x10) as *mut u32, low);
+    let low = ((trigger_mode as u32) << 15)
+        | ((level as u32) << 14)
+        | ((delivery_mode as u32) << 8)
+        | (vector as u32);
+    if is_x2apic_mode() {
+        wait_for_ipi_delivery();
+        /* x2APICではICRは64bitのMSR一つで、上位32bitが宛先 */
+        write_msr(
+            get_x2apic_msr(INTERRUPT_COMMAND_REGISTER),
+            ((destination as u64) << 32) | low as u64,
+        );
+    } else {
+        assert!(destination <= 0xff);
+        /* 書き込みの間に割り込みハンドラがIPIを送ると宛先が上書きされるため、割り込みを禁止する */
+        let interrupt_flag = save_and_disable_interrupt();
+        wait_for_ipi_delivery();
+        write_register(INTERRUPT_COMMAND_REGISTER + 0x10, destination << 24);
+        write_register(INTERRUPT_COMMAND_REGISTER, low);
+        restore_interrupt(interrupt_flag);
     }
 }
+
+fn error_interrupt_handler(_: &ExceptionFrame) {
+    pr_err!(
+        "Local APIC error on CPU(APIC ID: {}): ESR = {:#X}",
+        get_apic_id(),
+        read_error_status()
+    );
+}
@@ -0,0 +1,365 @@
+//! Local APIC Timer
+//!
+//! 各CPUのLocal APICに内蔵されているタイマーです。
+//! 周波数は機種によって異なるため、各CPUでACPI PM Timerと比べて測定してから使います。
+//! 割り込みのたびにCPUごとのtick数を1増やすので、CPUごとの時間の基準になります。
+//! TSC-Deadlineモード(TSCが指定した値に達したときに割り込む)に対応していてTSCが一定の速さで増える場合は
+//! そちらを使って割り込みのたびに次の期限を設定し、それ以外の場合は周期モードを使います。
+//! TSC-Deadlineモードの場合は、tickと並行してCPUごとに一つ任意の期限に割り込ませることもできます。
+//! tickを止めて、一定時間後に一度だけ割り込むone-shotモードで使うこともできます。
+
+use super::acpi_pm_timer::AcpiPmTimer;
+use super::ap::get_per_cpu_data;
+use super::interrupt::{set_interrupt_handler, ExceptionFrame};
+use super::local_apic::{read_register, write_lvt, write_register, Lvt, LVT_MASKED};
+use super::spin_lock::{restore_interrupt, save_and_disable_interrupt};
+use super::tsc::read_tsc;
+use super::{ACPI_PM_TIMER, TSC};
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Local APIC Timerの割り込みベクタ(全CPUで共通)
+pub const LOCAL_APIC_TIMER_VECTOR: u8 = 0xef;
+/// 周期モードの既定の割り込み間隔
+pub const DEFAULT_TICK_INTERVAL_MS: usize = 10;
+
+const INITIAL_COUNT_REGISTER: usize = 0x380;
+const CURRENT_COUNT_REGISTER: usize = 0x390;
+const DIVIDE_CONFIGURATION_REGISTER: usize = 0x3e0;
+
+/// 16分周
+const DIVIDE_BY_16: u32 = 0b0011;
+const LVT_TIMER_MODE_ONE_SHOT: u32 = 0b00 << 17;
+const LVT_TIMER_MODE_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+const IA32_TSC_DEADLINE_MSR: u32 = 0x6e0;
+/// 周波数の測定にかける時間
+const CALIBRATION_TIME_MS: usize = 10;
+/// check_tsc_deadline・check_one_shotで設定する期限
+const CHECK_DEADLINE_US: u64 = 1000;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum TimerMode {
+    Stopped,
+    /// 周期モードでtickを数える
+    Periodic,
+    /// TSC-Deadlineモードでtickを数える
+    TscDeadline,
+    /// start_one_shotで設定した時間に一度だけ割り込む
+    OneShot,
+}
+
+pub struct LocalApicTimer {
+    /// 分周後の周波数
+    frequency_hz: usize,
+    mode: TimerMode,
+    /// TSC-Deadlineモードの割り込み間隔(TSCのカウント数)
+    tsc_deadline_interval: u64,
+    /// TSC-Deadlineモードで次にtickとする割り込みのTSCの値
+    next_tick_deadline: AtomicU64,
+    /// start_tsc_deadlineで設定した期限(TSCの値)、設定されていない場合は0
+    deadline: AtomicU64,
+    /// start_one_shotで設定した割り込みがまだ発生していないか
+    is_one_shot_pending: AtomicBool,
+    /// tickの割り込みが発生した回数
+    tick_count: AtomicUsize,
+}
+
+impl LocalApicTimer {
+    pub const fn new() -> Self {
+        Self {
+            frequency_hz: 0,
+            mode: TimerMode::Stopped,
+            tsc_deadline_interval: 0,
+            next_tick_deadline: AtomicU64::new(0),
+            deadline: AtomicU64::new(0),
+            is_one_shot_pending: AtomicBool::new(false),
+            tick_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// このCPUのLocal APIC Timerの周波数をpm_timerで測定し、割り込みハンドラを設定する
+    ///
+    /// 割り込みを禁止した状態で呼び出してください。測定後のタイマーは止まっています。
+    pub fn init(&mut self, pm_timer: &AcpiPmTimer) {
+        set_interrupt_handler(LOCAL_APIC_TIMER_VECTOR, timer_interrupt_handler);
+        write_register(DIVIDE_CONFIGURATION_REGISTER, DIVIDE_BY_16);
+        write_lvt(
+            Lvt::Timer,
+            LVT_MASKED | LVT_TIMER_MODE_ONE_SHOT | LOCAL_APIC_TIMER_VECTOR as u32,
+        );
+
+        let calibration_count = AcpiPmTimer::FREQUENCY_HZ * CALIBRATION_TIME_MS / 1000;
+        let start = pm_timer.get_count();
+        write_register(INITIAL_COUNT_REGISTER, u32::MAX);
+        let elapsed = loop {
+            let elapsed = pm_timer.get_elapsed_count(start);
+            if elapsed >= calibration_count {
+                break elapsed;
+            }
+            core::hint::spin_loop();
+        };
+        let current = read_register(CURRENT_COUNT_REGISTER);
+        write_register(INITIAL_COUNT_REGISTER, 0);
+
+        self.frequency_hz = (u32::MAX - current) as usize * AcpiPmTimer::FREQUENCY_HZ / elapsed;
+    }
+
+    /// usをタイマーのカウント数に変換する(u32に収まらない場合は最大値)
+    fn us_to_count(&self, us: usize) -> u32 {
+        (self.frequency_hz.saturating_mul(us) / 1000000).clamp(1, u32::MAX as usize) as u32
+    }
+
+    /// interval_msごとに割り込みが発生するように起動する
+    ///
+    /// start_one_shotやstopで止めたtickもこれで再開します。
+    pub fn start_tick(&mut self, interval_ms: usize) {
+        self.is_one_shot_pending.store(false, Ordering::Relaxed);
+        /* 周波数が分からない場合は期限を求められないので周期モードを使う */
+        if unsafe { TSC.is_deadline_supported() && TSC.is_invariant() && TSC.get_frequency() != 0 }
+        {
+            self.tsc_deadline_interval =
+                unsafe { TSC.ns_to_count(interval_ms as u64 * 1000000) }.max(1);
+            self.mode = TimerMode::TscDeadline;
+            write_lvt(
+                Lvt::Timer,
+                LVT_TIMER_MODE_TSC_DEADLINE | LOCAL_APIC_TIMER_VECTOR as u32,
+            );
+            /* LVTへの書き込みがMSRへの書き込みより先に反映されるようにする */
+            unsafe { asm!("mfence", options(nostack)) };
+            self.next_tick_deadline
+                .store(read_tsc() + self.tsc_deadline_interval, Ordering::Relaxed);
+            self.set_next_deadline();
+        } else {
+            self.mode = TimerMode::Periodic;
+            self.deadline.store(0, Ordering::Relaxed);
+            write_lvt(
+                Lvt::Timer,
+                LVT_TIMER_MODE_PERIODIC | LOCAL_APIC_TIMER_VECTOR as u32,
+            );
+            write_register(INITIAL_COUNT_REGISTER, self.us_to_count(interval_ms * 1000));
+        }
+    }
+
+    /// TSCがdeadlineに達したときに一度だけ割り込みが発生するようにする
+    ///
+    /// tickはそのまま続きます。deadlineはTsc::get_deadline_after_nsで求められます。
+    /// 前に設定した期限は上書きされます。tickがTSC-Deadlineモードでない場合はfalseを返します。
+    pub fn start_tsc_deadline(&self, deadline: u64) -> bool {
+        if !self.is_tsc_deadline_mode() {
+            return false;
+        }
+        /* 割り込みハンドラが次の期限を設定するのと混ざらないようにする */
+        let interrupt_flag = save_and_disable_interrupt();
+        self.deadline.store(deadline.max(1), Ordering::Relaxed);
+        self.set_next_deadline();
+        restore_interrupt(interrupt_flag);
+        true
+    }
+
+    /// tickを止め、us後に一度だけ割り込みが発生するように起動する
+    ///
+    /// start_tsc_deadlineで設定した期限も取り消されます。tickはstart_tickで再開してください。
+    pub fn start_one_shot(&mut self, us: usize) {
+        self.stop();
+        self.mode = TimerMode::OneShot;
+        self.is_one_shot_pending.store(true, Ordering::Relaxed);
+        write_lvt(
+            Lvt::Timer,
+            LVT_TIMER_MODE_ONE_SHOT | LOCAL_APIC_TIMER_VECTOR as u32,
+        );
+        write_register(INITIAL_COUNT_REGISTER, self.us_to_count(us));
+    }
+
+    /// タイマーを止める(tick・start_tsc_deadline・start_one_shotのどれも割り込まなくなります)
+    pub fn stop(&mut self) {
+        self.mode = TimerMode::Stopped;
+        write_register(INITIAL_COUNT_REGISTER, 0);
+        if unsafe { TSC.is_deadline_supported() } {
+            write_tsc_deadline(0);
+        }
+        self.deadline.store(0, Ordering::Relaxed);
+        self.is_one_shot_pending.store(false, Ordering::Relaxed);
+    }
+
+    /// start_one_shotで設定した割り込みがまだ発生していないか
+    pub fn is_one_shot_pending(&self) -> bool {
+        self.is_one_shot_pending.load(Ordering::Relaxed)
+    }
+
+    /// start_tsc_deadlineで設定した期限がまだ来ていないか
+    pub fn is_deadline_pending(&self) -> bool {
+        self.deadline.load(Ordering::Relaxed) != 0
+    }
+
+    /// 次のtickとstart_tsc_deadlineの期限のうち早い方をIA32_TSC_DEADLINEに設定する
+    fn set_next_deadline(&self) {
+        let next_tick = self.next_tick_deadline.load(Ordering::Relaxed);
+        match self.deadline.load(Ordering::Relaxed) {
+            0 => write_tsc_deadline(next_tick),
+            deadline => write_tsc_deadline(deadline.min(next_tick)),
+        }
+    }
+
+    pub fn is_tsc_deadline_mode(&self) -> bool {
+        self.mode == TimerMode::TscDeadline
+    }
+
+    pub fn get_frequency(&self) -> usize {
+        self.frequency_hz
+    }
+
+    pub fn get_tick_count(&self) -> usize {
+        self.tick_count.load(Ordering::Relaxed)
+    }
+}
+
+/// このCPUのLocal APIC Timerの周波数を測定し、DEFAULT_TICK_INTERVAL_MSごとに割り込むように起動する
+///
+/// 各CPUでPerCpuDataを作成した後に呼び出してください。TSCの初期化後である必要があります。
+pub fn init_local_apic_timer(pm_timer: &AcpiPmTimer) {
+    let timer = &mut get_per_cpu_data().local_apic_timer;
+    timer.init(pm_timer);
+    timer.start_tick(DEFAULT_TICK_INTERVAL_MS);
+    pr_debug!(
+        "Local APIC Timer: {} Hz (TSC-Deadline: {})",
+        timer.get_frequency(),
+        timer.is_tsc_deadline_mode()
+    );
+}
+
+/// このCPUのLocal APIC Timerの割り込みが発生した回数
+pub fn get_tick_count() -> usize {
+    get_per_cpu_data().local_apic_timer.get_tick_count()
+}
+
+/// 割り込みを許可した後に呼び出し、このCPUでタイマー割り込みが発生しているかを確かめる
+///
+/// 割り込み間隔の10倍の時間が過ぎてもtick数が増えない場合はfalseを返します。
+pub fn is_local_apic_timer_working() -> bool {
+    let tick_count = get_tick_count();
+    wait_until(DEFAULT_TICK_INTERVAL_MS * 10, || {
+        get_tick_count() != tick_count
+    })
+}
+
+/// 割り込みを許可した後に呼び出し、tickと並行してTSC-Deadlineモードの期限に割り込みが発生するかを確かめる
+///
+/// 起動時の確認用です。tickがTSC-Deadlineモードでない場合は確かめずにtrueを返します。
+pub fn check_tsc_deadline() -> bool {
+    let timer = &get_per_cpu_data().local_apic_timer;
+    let deadline = unsafe { TSC.get_deadline_after_ns(CHECK_DEADLINE_US * 1000) };
+    if !timer.start_tsc_deadline(deadline) {
+        return true;
+    }
+    /* tickを挟んでも期限が失われないように、tickの間隔の10倍まで待つ */
+    wait_until(DEFAULT_TICK_INTERVAL_MS * 10, || {
+        !timer.is_deadline_pending()
+    }) && read_tsc() >= deadline
+}
+
+/// 割り込みを許可した後に呼び出し、tickを止めている間にone-shotモードの割り込みだけが発生するかを確かめる
+///
+/// 起動時の確認用です。確認後はDEFAULT_TICK_INTERVAL_MSごとのtickを再開します。
+pub fn check_one_shot() -> bool {
+    let timer = &mut get_per_cpu_data().local_apic_timer;
+    timer.start_one_shot(CHECK_DEADLINE_US as usize);
+    let tick_count = timer.get_tick_count();
+    let result = wait_until(DEFAULT_TICK_INTERVAL_MS * 10, || {
+        !timer.is_one_shot_pending()
+    }) && timer.get_tick_count() == tick_count;
+    timer.start_tick(DEFAULT_TICK_INTERVAL_MS);
+    result
+}
+
+/// condがtrueになるまで最大timeout_ms待ち、trueになったかを返す
+///
+/// TSCの周波数が分からない場合はACPI PM Timerで時間を測ります。
+/// timeout_msはPM Timerが一周する時間(24bitのカウンタでは約4.6秒)より短くしてください。
+fn wait_until(timeout_ms: usize, cond: impl Fn() -> bool) -> bool {
+    if unsafe { TSC.get_frequency() } != 0 {
+        let timeout = unsafe { TSC.get_monotonic_ns() } + timeout_ms as u64 * 1000000;
+        while !cond() {
+            if unsafe { TSC.get_monotonic_ns() } >= timeout {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+    } else {
+        let pm_timer = unsafe { &ACPI_PM_TIMER };
+        let timeout_count = AcpiPmTimer::FREQUENCY_HZ * timeout_ms / 1000;
+        let start = pm_timer.get_count();
+        while !cond() {
+            if pm_timer.get_elapsed_count(start) >= timeout_count {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+    }
+    true
+}
+
+/// IA32_TSC_DEADLINEに書き込む
+fn write_tsc_deadline(deadline: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") IA32_TSC_DEADLINE_MSR,
+            in("eax") deadline as u32,
+            in("edx") (deadline >> 32) as u32,
+            options(nostack)
+        )
+    };
+}
+
+fn timer_interrupt_handler(_: &ExceptionFrame) {
+    let timer = &get_per_cpu_data().local_apic_timer;
+    match timer.mode {
+        TimerMode::Stopped => return,
+        TimerMode::Periodic => {
+            timer.tick_count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        TimerMode::OneShot => {
+            /* 切り替える前のtickの割り込みが遅れて届いた場合と区別する */
+            if read_register(CURRENT_COUNT_REGISTER) == 0 {
+                timer.is_one_shot_pending.store(false, Ordering::Relaxed);
+            }
+            return;
+        }
+        TimerMode::TscDeadline => {}
+    }
+    /* TSC-Deadlineモードは一度しか割り込まないので、期限が来たものを処理して次の期限を設定する */
+    let now = read_tsc();
+    if now >= timer.next_tick_deadline.load(Ordering::Relaxed) {
+        timer.tick_count.fetch_add(1, Ordering::Relaxed);
+        timer
+            .next_tick_deadline
+            .store(now + timer.tsc_deadline_interval, Ordering::Relaxed);
+    }
+    let deadline = timer.deadline.load(Ordering::Relaxed);
+    if deadline != 0 && now >= deadline {
+        timer.deadline.store(0, Ordering::Relaxed);
+    }
+    timer.set_next_deadline();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn us_to_count_clamps_to_register_range() {
+        let timer = LocalApicTimer {
+            frequency_hz: 62500000,
+            ..LocalApicTimer::new()
+        };
+        assert_eq!(timer.us_to_count(1000), 62500);
+        assert_eq!(timer.us_to_count(1), 62);
+        /* 0にするとタイマーが止まってしまうので最小でも1 */
+        assert_eq!(timer.us_to_count(0), 1);
+        assert_eq!(timer.us_to_count(100000000), u32::MAX);
+        assert_eq!(timer.us_to_count(usize::MAX), u32::MAX);
+    }
+}
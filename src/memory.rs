@@ -1,146 +1,762 @@
 //! メモリ管理用モジュール
 //!
-//! Multiboot Informationのメモリ関係の情報をもとに空きメモリを管理し
-//! 貸し出してます。簡略化のためfreeの実装はしてません。
-
-use core::mem;
-
-#[derive(Clone)]
-#[repr(C)]
-struct MemoryMapEntry {
-    pub addr: u64,
-    pub length: u64,
-    pub m_type: u32,
-    pub reserved: u32,
+//! Multiboot Informationのメモリマップをもとに、物理メモリを4KiB単位のフレームとして
+//! ビットマップで管理し貸し出してます。
+//! ビットが1のフレームは使用中(または使用できないメモリ)です。
+//! 起動時に貸し出せたフレームも別のビットマップで覚えておき、二重解放や予約した領域の解放を検出します。
+//! ビットマップはスピンロックで保護しているので、すべてのコアから同時に呼び出せます。
+//!
+//! メモリマップ上で使用可能とされていても、カーネル本体・Multiboot Information・ブートモジュール・
+//! ACPIテーブル・フレームバッファ・先頭1MiBは貸し出さないように予約します。
+
+use super::multiboot2::{ElfSectionsTag, MemoryMapEntry, MemoryMapTag};
+use super::paging::{kernel_virt_to_phys, phys_to_virt};
+use super::spin_lock::SpinLock;
+
+pub const PAGE_SHIFT: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+
+/// ビットマップの配置に使用できるアドレスの上限
+/// boot.sでは先頭4GiBのみをダイレクトマップにマップしているため
+const MAX_BITMAP_ADDRESS: usize = 0x1_0000_0000;
+
+/// BIOSのデータ領域などが存在する可能性があるので貸し出さない範囲
+const LOW_MEMORY_SIZE: usize = 0x100000;
+/// APの起動用コードを置くために先頭1MiBから確保しておく領域の大きさ
+const REAL_MODE_AREA_SIZE: usize = PAGE_SIZE;
+
+/// 貸し出してはいけない領域
+#[derive(Clone, Copy)]
+pub struct ReservedArea {
+    pub address: usize,
+    pub size: usize,
+    pub name: &'static str,
 }
 
-#[repr(C)]
-#[allow(dead_code)]
-pub struct MultibootTagMemoryMap {
-    s_type: u32,
-    size: u32,
-    entry_size: u32,
-    entry_version: u32,
+pub struct ReservedAreaList {
+    areas: [ReservedArea; Self::MAX_NUM_OF_AREAS],
+    num_of_areas: usize,
 }
 
-#[repr(C)]
-pub struct MultibootTagElfSections {
-    s_type: u32,
-    size: u32,
-    num: u32,
-    entsize: u32,
-    shndx: u32,
+pub struct MemoryManager {
+    frame_bitmap: SpinLock<FrameBitmap>,
 }
 
-#[repr(C)]
-pub struct ElfSection {
-    section_name: u32,
-    section_type: u32,
-    section_flags: usize,
-    section_addr: usize,
-    section_offset: usize,
-    section_size: usize,
-    section_link: u32,
-    section_info: u32,
-    section_addralign: usize,
-    section_entry_size: usize,
+struct FrameBitmap {
+    real_mode_area: Option<usize>,
+    /// ビットマップの仮想アドレス
+    /// 使用中のフレームのビットマップの直後に、貸し出せるフレームのビットマップが続きます。
+    bitmap_address: usize,
+    /// ビットマップが管理しているフレームの数(最大の物理アドレス / PAGE_SIZE)
+    num_of_frames: usize,
+    /// 空きメモリとして報告されたフレームの数
+    num_of_usable_frames: usize,
+    num_of_free_frames: usize,
 }
 
-pub struct MemoryManager {
-    address: usize,
-    num_of_entries: u32,
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum FreeError {
+    /// アドレスがPAGE_SIZEに揃っていない
+    InvalidAlignment,
+    /// 使用不可のメモリや予約した領域を含んでいる
+    NotAllocatable,
+    /// 既に解放されている(確保されていない)フレームを含んでいる
+    DoubleFree,
+}
+
+/// valueをalign(2の累乗)の倍数に切り上げる
+pub const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+const fn align_down(value: usize, align: usize) -> usize {
+    value & !(align - 1)
+}
+
+/// 使用可能なエントリのうち、フレーム単位で使える範囲(開始, 終了)
+fn get_available_area(e: &MemoryMapEntry) -> Option<(usize, usize)> {
+    if !e.is_available() {
+        return None;
+    }
+    let start = align_up(e.base_address as usize, PAGE_SIZE);
+    let end = align_down((e.base_address + e.length) as usize, PAGE_SIZE);
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// memory_limitより前にある使用可能な領域を管理するのに必要なフレームの数
+fn get_num_of_frames(
+    entries: impl Iterator<Item = MemoryMapEntry>,
+    memory_limit: Option<usize>,
+) -> usize {
+    let max_address = entries
+        .filter_map(|e| get_available_area(&e))
+        .map(|(_, end)| end)
+        .max()
+        .unwrap_or(0);
+    align_down(
+        max_address.min(memory_limit.unwrap_or(usize::MAX)),
+        PAGE_SIZE,
+    ) >> PAGE_SHIFT
+}
+
+fn get_memory_type_name(memory_type: u32) -> &'static str {
+    match memory_type {
+        MemoryMapEntry::TYPE_AVAILABLE => "Available",
+        MemoryMapEntry::TYPE_ACPI_RECLAIMABLE => "ACPI Reclaimable",
+        MemoryMapEntry::TYPE_ACPI_NVS => "ACPI NVS",
+        MemoryMapEntry::TYPE_BAD_RAM => "Bad RAM",
+        _ => "Reserved",
+    }
+}
+
+impl ReservedAreaList {
+    const MAX_NUM_OF_AREAS: usize = 64;
+
+    pub const fn new() -> Self {
+        Self {
+            areas: [ReservedArea {
+                address: 0,
+                size: 0,
+                name: "",
+            }; Self::MAX_NUM_OF_AREAS],
+            num_of_areas: 0,
+        }
+    }
+
+    /// 同じ名前で隣接・重複している領域がある場合はまとめます。
+    pub fn add(&mut self, address: usize, size: usize, name: &'static str) {
+        if size == 0 {
+            return;
+        }
+        for area in self.areas[..self.num_of_areas].iter_mut() {
+            if area.name == name
+                && address <= area.address + area.size
+                && area.address <= address + size
+            {
+                let end = (area.address + area.size).max(address + size);
+                area.address = area.address.min(address);
+                area.size = end - area.address;
+                return;
+            }
+        }
+        if self.num_of_areas >= Self::MAX_NUM_OF_AREAS {
+            panic!("Too many reserved areas");
+        }
+        self.areas[self.num_of_areas] = ReservedArea {
+            address,
+            size,
+            name,
+        };
+        self.num_of_areas += 1;
+    }
+
+    pub fn as_slice(&self) -> &[ReservedArea] {
+        &self.areas[..self.num_of_areas]
+    }
 }
 
 impl MemoryManager {
     pub const fn const_new() -> Self {
         Self {
-            address: 0,
-            num_of_entries: 0,
+            frame_bitmap: SpinLock::new(FrameBitmap::const_new()),
         }
     }
 
-    pub fn new(map: &MultibootTagMemoryMap, elf_info: &MultibootTagElfSections) -> Self {
-        let m = Self {
-            num_of_entries: ((map.size - mem::size_of::<MultibootTagMemoryMap>() as u32)
-                / map.entry_size),
-            address: map as *const _ as usize + mem::size_of::<MultibootTagMemoryMap>(),
-        };
-        for i in 0..(elf_info.num as usize) {
-            let entry = unsafe {
-                &*((elf_info as *const _ as usize
-                    + mem::size_of::<MultibootTagElfSections>()
-                    + i * mem::size_of::<ElfSection>()) as *const ElfSection)
-            };
-            if entry.section_flags & 2 == 0 {
+    /// メモリマップからビットマップを作成する
+    ///
+    /// カーネルの各セクション・reserved_areas・先頭1MiBは使用中として扱います。
+    /// 使用可能なエントリと使用不可のエントリが重なっている場合は使用不可を優先します。
+    /// memory_limitが指定された場合はそれ以降の物理メモリを使用しません。
+    pub fn new(
+        map: &MemoryMapTag,
+        elf_sections: &ElfSectionsTag,
+        reserved_areas: &[ReservedArea],
+        memory_limit: Option<usize>,
+    ) -> Self {
+        Self {
+            frame_bitmap: SpinLock::new(FrameBitmap::new(
+                map,
+                elf_sections,
+                reserved_areas,
+                memory_limit,
+            )),
+        }
+    }
+
+    /// 2^order個の連続したフレームを確保する
+    /// 返されるアドレスは(PAGE_SIZE << order)に揃っています。解放はfreeで行います。
+    /* ヒープからのみ使うため、ヒープを除くテスト時は含めない */
+    #[cfg(not(test))]
+    pub fn alloc_frames(&self, order: usize) -> Option<usize> {
+        self.frame_bitmap.lock().alloc_frames(order)
+    }
+
+    /// sizeをPAGE_SIZE単位に切り上げて確保する
+    pub fn alloc(&self, size: usize) -> Option<usize> {
+        self.frame_bitmap.lock().alloc(size)
+    }
+
+    /// 確保したメモリを解放する
+    ///
+    /// 確保していないメモリや予約した領域を含む場合はエラーを表示して何もしません。
+    pub fn free(&self, address: usize, size: usize) {
+        let result = self.frame_bitmap.lock().free(address, size);
+        if let Err(e) = result {
+            pr_err!(
+                "Cannot free the memory({:#X} - {:#X}): {:?}",
+                address,
+                address + size,
+                e
+            );
+        }
+    }
+
+    /// alignに揃ったメモリを確保する
+    ///
+    /// alignは2の累乗である必要があります。PAGE_SIZE未満の場合はPAGE_SIZEに揃えます。
+    /* ヒープからのみ使うため、ヒープを除くテスト時は含めない */
+    #[cfg(not(test))]
+    pub fn alloc_with_align(&self, size: usize, align: usize) -> Option<usize> {
+        self.frame_bitmap
+            .lock()
+            .alloc_with_align(size, align, usize::MAX)
+    }
+
+    /// APの起動用コードを置くために先頭1MiBから確保しておいた領域(アドレス, サイズ)
+    pub fn get_real_mode_area(&self) -> Option<(usize, usize)> {
+        self.frame_bitmap
+            .lock()
+            .real_mode_area
+            .map(|a| (a, REAL_MODE_AREA_SIZE))
+    }
+
+    /// limit未満のアドレスからalignに揃ったメモリを確保する(DMA用のバッファなど)
+    pub fn alloc_with_align_below(&self, size: usize, align: usize, limit: usize) -> Option<usize> {
+        self.frame_bitmap
+            .lock()
+            .alloc_with_align(size, align, limit)
+    }
+
+    pub fn get_total_memory_size(&self) -> usize {
+        self.frame_bitmap.lock().get_total_memory_size()
+    }
+
+    pub fn get_free_memory_size(&self) -> usize {
+        self.frame_bitmap.lock().get_free_memory_size()
+    }
+
+    pub fn get_used_memory_size(&self) -> usize {
+        let frame_bitmap = self.frame_bitmap.lock();
+        frame_bitmap.get_total_memory_size() - frame_bitmap.get_free_memory_size()
+    }
+
+    /// 予約後の物理メモリの状態を表示する
+    pub fn print_memory_map(&self, map: &MemoryMapTag, reserved_areas: &[ReservedArea]) {
+        pr_debug!("Memory map from the boot loader:");
+        for e in map.entries() {
+            pr_debug!(
+                "  [{:#018X} - {:#018X}] {}",
+                e.base_address,
+                (e.base_address + e.length).max(1) - 1,
+                get_memory_type_name(e.memory_type)
+            );
+        }
+        let frame_bitmap = self.frame_bitmap.lock();
+        pr_info!("Reserved areas:");
+        pr_info!("  [{:#018X} - {:#018X}] Low memory", 0, LOW_MEMORY_SIZE - 1);
+        for area in reserved_areas {
+            pr_info!(
+                "  [{:#018X} - {:#018X}] {}",
+                area.address,
+                area.address + area.size - 1,
+                area.name
+            );
+        }
+        pr_info!("Usable memory:");
+        let mut frame = 0;
+        while frame < frame_bitmap.num_of_frames {
+            if frame_bitmap.is_frame_used(frame) {
+                frame += 1;
                 continue;
             }
-            m.reserve(entry.section_addr, entry.section_size);
+            let start = frame;
+            while frame < frame_bitmap.num_of_frames && !frame_bitmap.is_frame_used(frame) {
+                frame += 1;
+            }
+            pr_info!(
+                "  [{:#018X} - {:#018X}] {}KiB",
+                start << PAGE_SHIFT,
+                (frame << PAGE_SHIFT) - 1,
+                (frame - start) << (PAGE_SHIFT - 10)
+            );
+        }
+        pr_info!(
+            "Memory: {}KiB free / {}KiB",
+            frame_bitmap.get_free_memory_size() >> 10,
+            frame_bitmap.get_total_memory_size() >> 10
+        );
+    }
+}
+
+impl FrameBitmap {
+    const fn const_new() -> Self {
+        Self {
+            real_mode_area: None,
+            bitmap_address: 0,
+            num_of_frames: 0,
+            num_of_usable_frames: 0,
+            num_of_free_frames: 0,
+        }
+    }
+
+    fn new(
+        map: &MemoryMapTag,
+        elf_sections: &ElfSectionsTag,
+        reserved_areas: &[ReservedArea],
+        memory_limit: Option<usize>,
+    ) -> Self {
+        let kernel_areas = || {
+            elf_sections
+                .sections()
+                .filter(|s| s.is_allocated())
+                .map(|s| (kernel_virt_to_phys(s.address as usize), s.size as usize))
+        };
+
+        /* ビットマップが管理する範囲より後ろのフレームは使用されない */
+        let num_of_frames = get_num_of_frames(map.entries(), memory_limit);
+        /* 使用中のフレームと貸し出せるフレームの2つのビットマップ */
+        let bitmap_size = align_up(align_up(num_of_frames, 64) / 8 * 2, PAGE_SIZE);
+
+        /* ビットマップを置く場所を探す */
+        let mut bitmap_address = 0;
+        'search: for (start, end) in map.entries().filter_map(|e| get_available_area(&e)) {
+            let mut candidate = start.max(PAGE_SIZE);
+            let end = end.min(MAX_BITMAP_ADDRESS).min(num_of_frames << PAGE_SHIFT);
+            'retry: while candidate + bitmap_size <= end {
+                for (address, size) in
+                    kernel_areas().chain(reserved_areas.iter().map(|a| (a.address, a.size)))
+                {
+                    if address < candidate + bitmap_size && candidate < address + size {
+                        candidate = align_up(address + size, PAGE_SIZE);
+                        continue 'retry;
+                    }
+                }
+                bitmap_address = candidate;
+                break 'search;
+            }
+        }
+        if bitmap_address == 0 {
+            panic!(
+                "Cannot allocate the memory bitmap({:#X} bytes)",
+                bitmap_size
+            );
         }
+
+        let mut m = Self::with_bitmap(phys_to_virt(bitmap_address), num_of_frames);
+        m.init(
+            map.entries(),
+            kernel_areas()
+                .chain(reserved_areas.iter().map(|a| (a.address, a.size)))
+                .chain(core::iter::once((bitmap_address, bitmap_size))),
+        );
         m
     }
 
-    fn reserve(&self, address: usize, size: usize) {
-        for i in 0..(self.num_of_entries as usize) {
-            let entry = unsafe {
-                &mut *((self.address + i * mem::size_of::<MemoryMapEntry>()) as *mut MemoryMapEntry)
-            };
-            if entry.m_type != 1 {
-                continue;
+    /// bitmap_address(仮想アドレス)にあるnum_of_frames個のフレーム用のビットマップを使う
+    ///
+    /// ビットマップはalign_up(num_of_frames, 64) / 8 * 2 byteの大きさが必要です。
+    fn with_bitmap(bitmap_address: usize, num_of_frames: usize) -> Self {
+        Self {
+            bitmap_address,
+            num_of_frames,
+            ..Self::const_new()
+        }
+    }
+
+    /// メモリマップのエントリと予約する領域(アドレス, サイズ)からビットマップを初期化する
+    fn init(
+        &mut self,
+        entries: impl Iterator<Item = MemoryMapEntry> + Clone,
+        reserved_areas: impl Iterator<Item = (usize, usize)>,
+    ) {
+        self.get_bitmap_mut().fill(u64::MAX);
+        for (start, end) in entries.clone().filter_map(|e| get_available_area(&e)) {
+            self.set_frames_free(start >> PAGE_SHIFT, (end - start) >> PAGE_SHIFT);
+        }
+        /* 使用不可のエントリと重なっている部分は使用中にする */
+        for e in entries.filter(|e| !e.is_available()) {
+            let start = align_down(e.base_address as usize, PAGE_SIZE);
+            let end = align_up((e.base_address + e.length) as usize, PAGE_SIZE);
+            self.set_frames_used(start >> PAGE_SHIFT, (end - start) >> PAGE_SHIFT);
+        }
+        self.num_of_usable_frames = self.num_of_free_frames;
+
+        /* NULLと区別できないため0番地は貸し出さない */
+        self.reserve(0, PAGE_SIZE);
+        for (address, size) in reserved_areas {
+            self.reserve(address, size);
+        }
+
+        /* 先頭1MiBを予約する前にAPの起動用の領域を確保しておく */
+        if let Some(frame) = self.find_free_frames(
+            REAL_MODE_AREA_SIZE >> PAGE_SHIFT,
+            1,
+            LOW_MEMORY_SIZE >> PAGE_SHIFT,
+        ) {
+            self.set_frames_used(frame, REAL_MODE_AREA_SIZE >> PAGE_SHIFT);
+            self.real_mode_area = Some(frame << PAGE_SHIFT);
+        }
+        self.reserve(0, LOW_MEMORY_SIZE);
+
+        /* ここで空いているフレームだけが、以降に貸し出して解放されるフレーム */
+        let (used, allocatable) = self.get_bitmaps_mut();
+        for (a, u) in allocatable.iter_mut().zip(used.iter()) {
+            *a = !*u;
+        }
+    }
+
+    /// (使用中のフレームのビットマップ, 貸し出せるフレームのビットマップ)
+    fn get_bitmaps(&self) -> (&[u64], &[u64]) {
+        if self.bitmap_address == 0 {
+            return (&[], &[]);
+        }
+        let num_of_words = align_up(self.num_of_frames, 64) / 64;
+        unsafe { core::slice::from_raw_parts(self.bitmap_address as *const u64, num_of_words * 2) }
+            .split_at(num_of_words)
+    }
+
+    fn get_bitmaps_mut(&mut self) -> (&mut [u64], &mut [u64]) {
+        if self.bitmap_address == 0 {
+            return (&mut [], &mut []);
+        }
+        let num_of_words = align_up(self.num_of_frames, 64) / 64;
+        unsafe {
+            core::slice::from_raw_parts_mut(self.bitmap_address as *mut u64, num_of_words * 2)
+        }
+        .split_at_mut(num_of_words)
+    }
+
+    fn get_bitmap(&self) -> &[u64] {
+        self.get_bitmaps().0
+    }
+
+    fn get_bitmap_mut(&mut self) -> &mut [u64] {
+        self.get_bitmaps_mut().0
+    }
+
+    fn is_frame_used(&self, frame: usize) -> bool {
+        (self.get_bitmap()[frame / 64] & (1 << (frame % 64))) != 0
+    }
+
+    fn is_frame_allocatable(&self, frame: usize) -> bool {
+        (self.get_bitmaps().1[frame / 64] & (1 << (frame % 64))) != 0
+    }
+
+    fn set_frames_used(&mut self, start_frame: usize, num_of_frames: usize) {
+        for frame in start_frame..(start_frame + num_of_frames).min(self.num_of_frames) {
+            if !self.is_frame_used(frame) {
+                self.get_bitmap_mut()[frame / 64] |= 1 << (frame % 64);
+                self.num_of_free_frames -= 1;
             }
-            if (entry.addr as usize) < address
-                && ((entry.addr + entry.length) as usize) > address + size
-            {
-                entry.length -= (size as u64) + ((address as u64) - entry.addr);
-                entry.addr = (address + size) as u64;
-                return;
+        }
+    }
+
+    fn set_frames_free(&mut self, start_frame: usize, num_of_frames: usize) {
+        for frame in start_frame..(start_frame + num_of_frames).min(self.num_of_frames) {
+            if self.is_frame_used(frame) {
+                self.get_bitmap_mut()[frame / 64] &= !(1 << (frame % 64));
+                self.num_of_free_frames += 1;
             }
         }
     }
 
-    pub fn alloc(&self, size: usize) -> Option<usize> {
-        for i in 0..(self.num_of_entries as usize) {
-            let entry = unsafe {
-                &mut *((self.address + i * mem::size_of::<MemoryMapEntry>()) as *mut MemoryMapEntry)
-            };
-            if entry.m_type != 1 {
+    /// align_frames個単位に揃ったnum_of_frames個の連続した空きフレームをend_frameより前から探す
+    fn find_free_frames(
+        &self,
+        num_of_frames: usize,
+        align_frames: usize,
+        end_frame: usize,
+    ) -> Option<usize> {
+        let bitmap = self.get_bitmap();
+        let end_frame = end_frame.min(self.num_of_frames);
+        let mut frame = align_up(1, align_frames);
+        while frame + num_of_frames <= end_frame {
+            if frame.is_multiple_of(64) && bitmap[frame / 64] == u64::MAX {
+                /* 64フレームすべてが使用中 */
+                frame = align_up(frame + 64, align_frames);
                 continue;
             }
-            if entry.length as usize >= size {
-                if ((entry.addr as usize)..((entry.addr as usize) + size)).contains(&self.address) {
-                    if (entry.addr + entry.length) as usize
-                        - (self.address
-                            + (self.num_of_entries as usize) * mem::size_of::<MemoryMapEntry>())
-                        < size
-                    {
-                        continue;
-                    }
-                    let allocated_address = self.address
-                        + (self.num_of_entries as usize) * mem::size_of::<MemoryMapEntry>();
-                    entry.addr = (allocated_address + size) as u64;
-                    entry.length = ((entry.addr + entry.length) as usize
-                        - (self.address
-                            + (self.num_of_entries as usize) * mem::size_of::<MemoryMapEntry>())
-                        - size) as u64;
-                    return Some(allocated_address);
-                }
-                let allocated_address = entry.addr as usize;
-                entry.addr += size as u64;
-                entry.length -= size as u64;
-                return Some(allocated_address);
+            match (0..num_of_frames).find(|i| self.is_frame_used(frame + i)) {
+                Some(i) => frame = align_up(frame + i + 1, align_frames),
+                None => return Some(frame),
             }
         }
         None
     }
 
-    pub fn alloc_with_align(&self, size: usize, align: usize) -> Option<usize> {
-        let mut address = if size < align {
-            self.alloc(align)?
-        } else {
-            self.alloc(size + align)?
-        };
-        if address != 0 {
-            address += align - (address & (align - 1));
+    fn reserve(&mut self, address: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+        let start_frame = address >> PAGE_SHIFT;
+        let end_frame = align_up(address + size, PAGE_SIZE) >> PAGE_SHIFT;
+        self.set_frames_used(start_frame, end_frame - start_frame);
+    }
+
+    fn alloc_with_align(&mut self, size: usize, align: usize, limit: usize) -> Option<usize> {
+        if size == 0 || !align.is_power_of_two() {
+            return None;
+        }
+        let num_of_frames = align_up(size, PAGE_SIZE) >> PAGE_SHIFT;
+        let align_frames = align.max(PAGE_SIZE) >> PAGE_SHIFT;
+        let frame = self.find_free_frames(num_of_frames, align_frames, limit >> PAGE_SHIFT)?;
+        self.set_frames_used(frame, num_of_frames);
+        Some(frame << PAGE_SHIFT)
+    }
+
+    fn alloc_frames(&mut self, order: usize) -> Option<usize> {
+        self.alloc_with_align(PAGE_SIZE << order, PAGE_SIZE << order, usize::MAX)
+    }
+
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        self.alloc_with_align(size, PAGE_SIZE, usize::MAX)
+    }
+
+    /// 範囲の全てのフレームが貸し出したものであることを確認してから解放する
+    fn free(&mut self, address: usize, size: usize) -> Result<(), FreeError> {
+        if (address & (PAGE_SIZE - 1)) != 0 {
+            return Err(FreeError::InvalidAlignment);
+        }
+        let start_frame = address >> PAGE_SHIFT;
+        let num_of_frames = align_up(size, PAGE_SIZE) >> PAGE_SHIFT;
+        let end_frame = start_frame
+            .checked_add(num_of_frames)
+            .filter(|end| *end <= self.num_of_frames)
+            .ok_or(FreeError::NotAllocatable)?;
+        for frame in start_frame..end_frame {
+            if !self.is_frame_allocatable(frame) {
+                return Err(FreeError::NotAllocatable);
+            } else if !self.is_frame_used(frame) {
+                return Err(FreeError::DoubleFree);
+            }
+        }
+        self.set_frames_free(start_frame, num_of_frames);
+        Ok(())
+    }
+
+    fn get_total_memory_size(&self) -> usize {
+        self.num_of_usable_frames << PAGE_SHIFT
+    }
+
+    fn get_free_memory_size(&self) -> usize {
+        self.num_of_free_frames << PAGE_SHIFT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL_AREA: (usize, usize) = (0x200000, 0x100000);
+
+    fn entry(base_address: usize, length: usize, memory_type: u32) -> MemoryMapEntry {
+        MemoryMapEntry {
+            base_address: base_address as u64,
+            length: length as u64,
+            memory_type,
+        }
+    }
+
+    /// 先頭1MiB・カーネル・ファームウェアの領域を含む16MiBのメモリマップ
+    fn test_entries() -> [MemoryMapEntry; 5] {
+        [
+            entry(0, 0x9f000, MemoryMapEntry::TYPE_AVAILABLE),
+            entry(0x9f000, 0x61000, 2),
+            entry(0x100000, 0x700000, MemoryMapEntry::TYPE_AVAILABLE),
+            entry(0x800000, 0x100000, MemoryMapEntry::TYPE_ACPI_NVS),
+            /* 終わりがページに揃っていない */
+            entry(0x900000, 0x6ff800, MemoryMapEntry::TYPE_AVAILABLE),
+        ]
+    }
+
+    fn create_frame_bitmap(
+        entries: &[MemoryMapEntry],
+        reserved: &[(usize, usize)],
+        memory_limit: Option<usize>,
+    ) -> FrameBitmap {
+        let num_of_frames = get_num_of_frames(entries.iter().copied(), memory_limit);
+        let bitmap = vec![0u64; align_up(num_of_frames, 64) / 64 * 2].leak();
+        let mut m = FrameBitmap::with_bitmap(bitmap.as_mut_ptr() as usize, num_of_frames);
+        m.init(entries.iter().copied(), reserved.iter().copied());
+        m
+    }
+
+    fn create_test_frame_bitmap() -> FrameBitmap {
+        create_frame_bitmap(&test_entries(), &[KERNEL_AREA], None)
+    }
+
+    #[test]
+    fn init_statistics() {
+        let m = create_test_frame_bitmap();
+        assert_eq!(m.num_of_frames, 0xfff);
+        /* 0x9f + 0x700 + 0x6ff */
+        assert_eq!(m.get_total_memory_size(), 0xe9e << PAGE_SHIFT);
+        /* 先頭1MiBとカーネルは使えない */
+        assert_eq!(
+            m.get_free_memory_size(),
+            (0x700 - 0x100 + 0x6ff) << PAGE_SHIFT
+        );
+        let real_mode_area = m.real_mode_area.unwrap();
+        assert!(real_mode_area >= PAGE_SIZE && real_mode_area + REAL_MODE_AREA_SIZE <= 0x9f000);
+    }
+
+    #[test]
+    fn alloc_and_free() {
+        let mut m = create_test_frame_bitmap();
+        let free_size = m.get_free_memory_size();
+        let address = m.alloc(0x2800).unwrap();
+        assert_eq!(m.get_free_memory_size(), free_size - 0x3000);
+        assert_eq!(m.free(address, 0x2800), Ok(()));
+        assert_eq!(m.get_free_memory_size(), free_size);
+        /* 同じ場所を再び貸し出せる */
+        assert_eq!(m.alloc(0x3000), Some(address));
+    }
+
+    #[test]
+    fn free_rejects_double_free() {
+        let mut m = create_test_frame_bitmap();
+        let address = m.alloc(PAGE_SIZE * 2).unwrap();
+        assert_eq!(m.free(address + PAGE_SIZE, PAGE_SIZE), Ok(()));
+        let free_size = m.get_free_memory_size();
+        /* 一部が解放済みの場合は何も解放しない */
+        assert_eq!(m.free(address, PAGE_SIZE * 2), Err(FreeError::DoubleFree));
+        assert_eq!(m.get_free_memory_size(), free_size);
+        assert!(m.is_frame_used(address >> PAGE_SHIFT));
+        assert_eq!(m.free(address, PAGE_SIZE), Ok(()));
+        assert_eq!(m.free(address, PAGE_SIZE), Err(FreeError::DoubleFree));
+    }
+
+    #[test]
+    fn free_rejects_reserved_frames() {
+        let mut m = create_test_frame_bitmap();
+        let free_size = m.get_free_memory_size();
+        for (address, size) in [
+            (0, PAGE_SIZE),
+            (0x1000, PAGE_SIZE),
+            (m.real_mode_area.unwrap(), REAL_MODE_AREA_SIZE),
+            KERNEL_AREA,
+            (0x800000, PAGE_SIZE),
+            (0xfff000, PAGE_SIZE),
+            (usize::MAX & !(PAGE_SIZE - 1), PAGE_SIZE * 2),
+        ] {
+            assert_eq!(
+                m.free(address, size),
+                Err(FreeError::NotAllocatable),
+                "{:#X}",
+                address
+            );
+        }
+        assert_eq!(m.get_free_memory_size(), free_size);
+    }
+
+    #[test]
+    fn free_rejects_unaligned_address() {
+        let mut m = create_test_frame_bitmap();
+        let address = m.alloc(PAGE_SIZE).unwrap();
+        assert_eq!(m.free(address + 8, 8), Err(FreeError::InvalidAlignment));
+        assert!(m.is_frame_used(address >> PAGE_SHIFT));
+    }
+
+    #[test]
+    fn alloc_with_power_of_two_align() {
+        let mut m = create_test_frame_bitmap();
+        for align in [PAGE_SIZE * 2, 0x10000, 0x200000] {
+            let address = m.alloc_with_align(PAGE_SIZE, align, usize::MAX).unwrap();
+            assert_eq!(address & (align - 1), 0, "{:#X}", align);
+        }
+        assert_eq!(m.alloc_with_align(PAGE_SIZE, 0x3000, usize::MAX), None);
+        assert_eq!(m.alloc_with_align(0, PAGE_SIZE, usize::MAX), None);
+    }
+
+    #[test]
+    fn alloc_with_align_smaller_than_page_size() {
+        let mut m = create_test_frame_bitmap();
+        let free_size = m.get_free_memory_size();
+        let address = m.alloc_with_align(0x10, 8, usize::MAX).unwrap();
+        assert_eq!(address & (PAGE_SIZE - 1), 0);
+        assert_eq!(m.get_free_memory_size(), free_size - PAGE_SIZE);
+    }
+
+    #[test]
+    fn alloc_with_size_not_multiple_of_align() {
+        let mut m = create_test_frame_bitmap();
+        let free_size = m.get_free_memory_size();
+        let address = m.alloc_with_align(0x3000, 0x2000, usize::MAX).unwrap();
+        assert_eq!(address & 0x1fff, 0);
+        assert_eq!(m.get_free_memory_size(), free_size - 0x3000);
+        /* 余りの部分は次の確保に使われる */
+        assert_eq!(m.alloc(PAGE_SIZE), Some(address + 0x3000));
+    }
+
+    #[test]
+    fn alloc_with_align_below_limit() {
+        let mut m = create_test_frame_bitmap();
+        let limit = KERNEL_AREA.0;
+        let mut count = 0;
+        while let Some(address) = m.alloc_with_align(0x10000, 0x10000, limit) {
+            assert!(address >= LOW_MEMORY_SIZE && address + 0x10000 <= limit);
+            count += 1;
+        }
+        assert_eq!(count, (limit - LOW_MEMORY_SIZE) / 0x10000);
+        assert!(m.alloc_with_align(0x10000, 0x10000, usize::MAX).unwrap() >= limit);
+    }
+
+    #[test]
+    fn alloc_frames_returns_aligned_blocks() {
+        let mut m = create_test_frame_bitmap();
+        let mut blocks = Vec::new();
+        while let Some(address) = m.alloc_frames(9) {
+            blocks.push(address);
+        }
+        assert_eq!(blocks, [0x400000, 0x600000, 0xa00000, 0xc00000]);
+    }
+
+    #[test]
+    fn alloc_only_from_available_entries() {
+        let entries = test_entries();
+        let mut m = create_frame_bitmap(&entries, &[KERNEL_AREA], None);
+        let num_of_free_frames = m.get_free_memory_size() >> PAGE_SHIFT;
+        let mut count = 0;
+        while let Some(address) = m.alloc(PAGE_SIZE) {
+            assert!(entries.iter().any(|e| {
+                e.is_available()
+                    && e.base_address as usize <= address
+                    && address + PAGE_SIZE <= (e.base_address + e.length) as usize
+            }));
+            assert!(address >= LOW_MEMORY_SIZE);
+            assert!(
+                address + PAGE_SIZE <= KERNEL_AREA.0 || KERNEL_AREA.0 + KERNEL_AREA.1 <= address
+            );
+            count += 1;
+        }
+        assert_eq!(count, num_of_free_frames);
+        assert_eq!(m.get_free_memory_size(), 0);
+    }
+
+    #[test]
+    fn memory_limit_caps_usable_frames() {
+        /* ページに揃っていない上限は切り下げる */
+        let mut m = create_frame_bitmap(&test_entries(), &[KERNEL_AREA], Some(0x600800));
+        assert_eq!(m.num_of_frames, 0x600);
+        assert_eq!(m.get_total_memory_size(), (0x9f + 0x500) << PAGE_SHIFT);
+        assert_eq!(m.get_free_memory_size(), (0x500 - 0x100) << PAGE_SHIFT);
+        while let Some(address) = m.alloc(PAGE_SIZE) {
+            assert!(address + PAGE_SIZE <= 0x600000);
         }
-        Some(address)
+        assert_eq!(m.free(0x600000, PAGE_SIZE), Err(FreeError::NotAllocatable));
     }
 }
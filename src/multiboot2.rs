@@ -0,0 +1,854 @@
+//! Multiboot2 Information解析用モジュール
+//!
+//! ブートローダーから渡されるMultiboot2 Informationを検証し、各タグを型付きで取り出せるようにします。
+//! total_sizeと各タグのsizeを最初にすべて検証するので、壊れた情報を渡された場合は
+//! メモリの範囲外を読みに行く前にエラーを返します。
+//! https://www.gnu.org/software/grub/manual/multiboot2/multiboot.html
+
+use super::paging::phys_to_virt;
+
+use core::fmt;
+
+const TAG_TYPE_END: u32 = 0;
+const TAG_TYPE_CMDLINE: u32 = 1;
+const TAG_TYPE_BOOT_LOADER_NAME: u32 = 2;
+const TAG_TYPE_MODULE: u32 = 3;
+const TAG_TYPE_BASIC_MEMINFO: u32 = 4;
+const TAG_TYPE_BOOTDEV: u32 = 5;
+const TAG_TYPE_MMAP: u32 = 6;
+const TAG_TYPE_FRAMEBUFFER: u32 = 8;
+const TAG_TYPE_ELF_SECTIONS: u32 = 9;
+const TAG_TYPE_APM: u32 = 10;
+const TAG_TYPE_EFI32: u32 = 11;
+const TAG_TYPE_EFI64: u32 = 12;
+const TAG_TYPE_SMBIOS: u32 = 13;
+const TAG_TYPE_ACPI_OLD: u32 = 14;
+const TAG_TYPE_ACPI_NEW: u32 = 15;
+const TAG_TYPE_NETWORK: u32 = 16;
+const TAG_TYPE_EFI_MMAP: u32 = 17;
+const TAG_TYPE_EFI_BS: u32 = 18;
+const TAG_TYPE_EFI32_IH: u32 = 19;
+const TAG_TYPE_EFI64_IH: u32 = 20;
+const TAG_TYPE_LOAD_BASE_ADDR: u32 = 21;
+
+/// タグの共通ヘッダ(type + size)の大きさ
+const TAG_HEADER_SIZE: usize = 8;
+/// ELF64のセクションヘッダの大きさ
+const ELF64_SECTION_HEADER_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub enum MultibootError {
+    InvalidAddress(usize),
+    InvalidTotalSize(u32),
+    InvalidTagSize {
+        tag_type: u32,
+        size: u32,
+        offset: usize,
+    },
+    EndTagNotFound,
+}
+
+/// 検証済みのMultiboot2 Information
+#[derive(Clone)]
+pub struct MultibootInformation {
+    address: usize,
+    data: &'static [u8],
+}
+
+#[derive(Clone)]
+pub struct MultibootTagIter {
+    data: &'static [u8],
+    offset: usize,
+}
+
+pub enum MultibootTag {
+    CommandLine(&'static str),
+    BootLoaderName(&'static str),
+    Module(ModuleTag),
+    BasicMemoryInfo(BasicMemoryInfoTag),
+    BiosBootDevice(BiosBootDeviceTag),
+    MemoryMap(MemoryMapTag),
+    FrameBuffer(FrameBufferTag),
+    ElfSections(ElfSectionsTag),
+    Apm(ApmTag),
+    Efi32SystemTable(u32),
+    Efi64SystemTable(u64),
+    Smbios(SmbiosTag),
+    /// RSDP(ACPI 1.0)のコピー
+    AcpiOld(&'static [u8]),
+    /// RSDP(ACPI 2.0以降)のコピー
+    AcpiNew(&'static [u8]),
+    /// DHCP ACKパケット
+    Network(&'static [u8]),
+    EfiMemoryMap(EfiMemoryMapTag),
+    EfiBootServicesNotTerminated,
+    Efi32ImageHandle(u32),
+    Efi64ImageHandle(u64),
+    LoadBaseAddress(u32),
+    Unknown {
+        tag_type: u32,
+        data: &'static [u8],
+    },
+}
+
+#[derive(Clone, Copy)]
+pub struct ModuleTag {
+    pub start_address: u32,
+    pub end_address: u32,
+    pub string: &'static str,
+}
+
+#[derive(Clone, Copy)]
+pub struct BasicMemoryInfoTag {
+    /// KiB単位
+    pub memory_lower: u32,
+    /// KiB単位
+    pub memory_upper: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct BiosBootDeviceTag {
+    pub bios_device: u32,
+    pub partition: u32,
+    pub sub_partition: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct MemoryMapTag {
+    entry_size: usize,
+    entries: &'static [u8],
+}
+
+#[derive(Clone)]
+pub struct MemoryMapEntryIter {
+    entry_size: usize,
+    entries: &'static [u8],
+    pointer: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub base_address: u64,
+    pub length: u64,
+    pub memory_type: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct FrameBufferTag {
+    pub address: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    pub color_info: FrameBufferColorInfo,
+}
+
+#[derive(Clone, Copy)]
+pub enum FrameBufferColorInfo {
+    /// パレットは(red, green, blue)の3byteずつ並んでいます
+    Indexed {
+        num_colors: u16,
+        palette: &'static [u8],
+    },
+    Rgb {
+        red_position: u8,
+        red_mask_size: u8,
+        green_position: u8,
+        green_mask_size: u8,
+        blue_position: u8,
+        blue_mask_size: u8,
+    },
+    EgaText,
+    Unknown(u8),
+}
+
+#[derive(Clone, Copy)]
+pub struct ElfSectionsTag {
+    num: usize,
+    entry_size: usize,
+    sections: &'static [u8],
+}
+
+#[derive(Clone)]
+pub struct ElfSectionIter {
+    tag: ElfSectionsTag,
+    index: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct ElfSection {
+    pub flags: u64,
+    pub address: u64,
+    pub size: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct ApmTag {
+    pub version: u16,
+    pub code_segment: u16,
+    pub offset: u32,
+    pub code_segment_16: u16,
+    pub data_segment: u16,
+    pub flags: u16,
+    pub code_segment_length: u16,
+    pub code_segment_16_length: u16,
+    pub data_segment_length: u16,
+}
+
+#[derive(Clone, Copy)]
+pub struct SmbiosTag {
+    pub major: u8,
+    pub minor: u8,
+    pub tables: &'static [u8],
+}
+
+#[derive(Clone, Copy)]
+pub struct EfiMemoryMapTag {
+    pub descriptor_size: u32,
+    pub descriptor_version: u32,
+    pub memory_map: &'static [u8],
+}
+
+fn read_u8(data: &[u8], offset: usize) -> u8 {
+    data[offset]
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..(offset + 4)]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..(offset + 8)]);
+    u64::from_le_bytes(bytes)
+}
+
+/// \0で終わる文字列を取り出す。UTF-8として不正な場合は空文字列を返す。
+fn read_c_str(data: &'static [u8]) -> &'static str {
+    let length = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+    core::str::from_utf8(&data[..length]).unwrap_or("")
+}
+
+/// タグの種類ごとの最小サイズ(ヘッダを含む)
+fn get_minimum_tag_size(tag_type: u32) -> usize {
+    match tag_type {
+        TAG_TYPE_END | TAG_TYPE_EFI_BS => 8,
+        TAG_TYPE_CMDLINE | TAG_TYPE_BOOT_LOADER_NAME => 9,
+        TAG_TYPE_MODULE => 17,
+        TAG_TYPE_BASIC_MEMINFO => 16,
+        TAG_TYPE_BOOTDEV => 20,
+        TAG_TYPE_MMAP | TAG_TYPE_EFI_MMAP | TAG_TYPE_SMBIOS => 16,
+        TAG_TYPE_FRAMEBUFFER => 32,
+        TAG_TYPE_ELF_SECTIONS => 20,
+        TAG_TYPE_APM => 28,
+        TAG_TYPE_EFI32 | TAG_TYPE_EFI32_IH | TAG_TYPE_LOAD_BASE_ADDR => 12,
+        TAG_TYPE_EFI64 | TAG_TYPE_EFI64_IH => 16,
+        /* RSDP 1.0は20byte */
+        TAG_TYPE_ACPI_OLD => 8 + 20,
+        /* RSDP 2.0は36byte */
+        TAG_TYPE_ACPI_NEW => 8 + 36,
+        _ => TAG_HEADER_SIZE,
+    }
+}
+
+/// タグの中身がsizeに収まっているかを確認する
+fn validate_tag_body(tag_type: u32, tag: &[u8]) -> bool {
+    match tag_type {
+        TAG_TYPE_MMAP => {
+            let entry_size = read_u32(tag, 8) as usize;
+            entry_size >= 24 && (entry_size & 7) == 0
+        }
+        TAG_TYPE_ELF_SECTIONS => {
+            let num = read_u32(tag, 8) as usize;
+            let entry_size = read_u32(tag, 12) as usize;
+            if num == 0 {
+                return true;
+            }
+            entry_size >= ELF64_SECTION_HEADER_SIZE
+                && num
+                    .checked_mul(entry_size)
+                    .map(|s| s <= tag.len() - 20)
+                    .unwrap_or(false)
+        }
+        TAG_TYPE_FRAMEBUFFER => match read_u8(tag, 29) {
+            0 => tag.len() >= 34 && 34 + read_u16(tag, 32) as usize * 3 <= tag.len(),
+            1 => tag.len() >= 38,
+            _ => true,
+        },
+        TAG_TYPE_EFI_MMAP => read_u32(tag, 8) != 0,
+        _ => true,
+    }
+}
+
+impl fmt::Display for MultibootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAddress(address) => write!(f, "invalid address {:#X}", address),
+            Self::InvalidTotalSize(size) => write!(f, "invalid total size {:#X}", size),
+            Self::InvalidTagSize {
+                tag_type,
+                size,
+                offset,
+            } => write!(
+                f,
+                "invalid size {:#X} of the tag (type: {}) at offset {:#X}",
+                size, tag_type, offset
+            ),
+            Self::EndTagNotFound => write!(f, "end tag is not found"),
+        }
+    }
+}
+
+impl MultibootInformation {
+    /// 物理アドレスaddressにあるMultiboot2 Informationを検証する
+    ///
+    /// addressから(total_sizeの分だけ)ダイレクトマップ経由で読めることは呼び出し側で保証してください。
+    pub unsafe fn new(address: usize) -> Result<Self, MultibootError> {
+        if address == 0 || address & 7 != 0 {
+            return Err(MultibootError::InvalidAddress(address));
+        }
+        let total_size = core::ptr::read_volatile(phys_to_virt(address) as *const u32);
+        if (total_size as usize) < TAG_HEADER_SIZE * 2 || total_size & 7 != 0 {
+            return Err(MultibootError::InvalidTotalSize(total_size));
+        }
+        Self::from_slice(
+            address,
+            core::slice::from_raw_parts(phys_to_virt(address) as *const u8, total_size as usize),
+        )
+    }
+
+    /// dataの全体を(total_sizeを含めて)検証する
+    fn from_slice(address: usize, data: &'static [u8]) -> Result<Self, MultibootError> {
+        if data.len() < TAG_HEADER_SIZE * 2 || read_u32(data, 0) as usize != data.len() {
+            return Err(MultibootError::InvalidTotalSize(data.len() as u32));
+        }
+        let info = Self { address, data };
+        info.validate()?;
+        Ok(info)
+    }
+
+    fn validate(&self) -> Result<(), MultibootError> {
+        let mut offset = TAG_HEADER_SIZE;
+        while offset + TAG_HEADER_SIZE <= self.data.len() {
+            let tag_type = read_u32(self.data, offset);
+            let size = read_u32(self.data, offset + 4);
+            let invalid_size = MultibootError::InvalidTagSize {
+                tag_type,
+                size,
+                offset,
+            };
+            if (size as usize) < get_minimum_tag_size(tag_type)
+                || offset + size as usize > self.data.len()
+            {
+                return Err(invalid_size);
+            }
+            if tag_type == TAG_TYPE_END {
+                return if size as usize == TAG_HEADER_SIZE {
+                    Ok(())
+                } else {
+                    Err(invalid_size)
+                };
+            }
+            if !validate_tag_body(tag_type, &self.data[offset..(offset + size as usize)]) {
+                return Err(invalid_size);
+            }
+            offset += (size as usize + 7) & !7;
+        }
+        Err(MultibootError::EndTagNotFound)
+    }
+
+    /// Multiboot2 Informationの物理アドレス
+    pub fn get_address(&self) -> usize {
+        self.address
+    }
+
+    pub fn get_total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn tags(&self) -> MultibootTagIter {
+        MultibootTagIter {
+            data: self.data,
+            offset: TAG_HEADER_SIZE,
+        }
+    }
+}
+
+impl Iterator for MultibootTagIter {
+    type Item = MultibootTag;
+    fn next(&mut self) -> Option<Self::Item> {
+        /* validate()で検証済みなので、ここでは範囲外を読むことはない */
+        if self.offset + TAG_HEADER_SIZE > self.data.len() {
+            return None;
+        }
+        let tag_type = read_u32(self.data, self.offset);
+        let size = read_u32(self.data, self.offset + 4) as usize;
+        if tag_type == TAG_TYPE_END {
+            self.offset = self.data.len();
+            return None;
+        }
+        let tag = &self.data[self.offset..(self.offset + size)];
+        self.offset += (size + 7) & !7;
+
+        Some(match tag_type {
+            TAG_TYPE_CMDLINE => MultibootTag::CommandLine(read_c_str(&tag[8..])),
+            TAG_TYPE_BOOT_LOADER_NAME => MultibootTag::BootLoaderName(read_c_str(&tag[8..])),
+            TAG_TYPE_MODULE => MultibootTag::Module(ModuleTag {
+                start_address: read_u32(tag, 8),
+                end_address: read_u32(tag, 12),
+                string: read_c_str(&tag[16..]),
+            }),
+            TAG_TYPE_BASIC_MEMINFO => MultibootTag::BasicMemoryInfo(BasicMemoryInfoTag {
+                memory_lower: read_u32(tag, 8),
+                memory_upper: read_u32(tag, 12),
+            }),
+            TAG_TYPE_BOOTDEV => MultibootTag::BiosBootDevice(BiosBootDeviceTag {
+                bios_device: read_u32(tag, 8),
+                partition: read_u32(tag, 12),
+                sub_partition: read_u32(tag, 16),
+            }),
+            TAG_TYPE_MMAP => MultibootTag::MemoryMap(MemoryMapTag {
+                entry_size: read_u32(tag, 8) as usize,
+                entries: &tag[16..],
+            }),
+            TAG_TYPE_FRAMEBUFFER => MultibootTag::FrameBuffer(FrameBufferTag {
+                address: read_u64(tag, 8),
+                pitch: read_u32(tag, 16),
+                width: read_u32(tag, 20),
+                height: read_u32(tag, 24),
+                bpp: read_u8(tag, 28),
+                color_info: match read_u8(tag, 29) {
+                    0 => {
+                        let num_colors = read_u16(tag, 32);
+                        FrameBufferColorInfo::Indexed {
+                            num_colors,
+                            palette: &tag[34..(34 + num_colors as usize * 3)],
+                        }
+                    }
+                    1 => FrameBufferColorInfo::Rgb {
+                        red_position: read_u8(tag, 32),
+                        red_mask_size: read_u8(tag, 33),
+                        green_position: read_u8(tag, 34),
+                        green_mask_size: read_u8(tag, 35),
+                        blue_position: read_u8(tag, 36),
+                        blue_mask_size: read_u8(tag, 37),
+                    },
+                    2 => FrameBufferColorInfo::EgaText,
+                    t => FrameBufferColorInfo::Unknown(t),
+                },
+            }),
+            TAG_TYPE_ELF_SECTIONS => MultibootTag::ElfSections(ElfSectionsTag {
+                num: read_u32(tag, 8) as usize,
+                entry_size: read_u32(tag, 12) as usize,
+                sections: &tag[20..],
+            }),
+            TAG_TYPE_APM => MultibootTag::Apm(ApmTag {
+                version: read_u16(tag, 8),
+                code_segment: read_u16(tag, 10),
+                offset: read_u32(tag, 12),
+                code_segment_16: read_u16(tag, 16),
+                data_segment: read_u16(tag, 18),
+                flags: read_u16(tag, 20),
+                code_segment_length: read_u16(tag, 22),
+                code_segment_16_length: read_u16(tag, 24),
+                data_segment_length: read_u16(tag, 26),
+            }),
+            TAG_TYPE_EFI32 => MultibootTag::Efi32SystemTable(read_u32(tag, 8)),
+            TAG_TYPE_EFI64 => MultibootTag::Efi64SystemTable(read_u64(tag, 8)),
+            TAG_TYPE_SMBIOS => MultibootTag::Smbios(SmbiosTag {
+                major: read_u8(tag, 8),
+                minor: read_u8(tag, 9),
+                tables: &tag[16..],
+            }),
+            TAG_TYPE_ACPI_OLD => MultibootTag::AcpiOld(&tag[8..]),
+            TAG_TYPE_ACPI_NEW => MultibootTag::AcpiNew(&tag[8..]),
+            TAG_TYPE_NETWORK => MultibootTag::Network(&tag[8..]),
+            TAG_TYPE_EFI_MMAP => MultibootTag::EfiMemoryMap(EfiMemoryMapTag {
+                descriptor_size: read_u32(tag, 8),
+                descriptor_version: read_u32(tag, 12),
+                memory_map: &tag[16..],
+            }),
+            TAG_TYPE_EFI_BS => MultibootTag::EfiBootServicesNotTerminated,
+            TAG_TYPE_EFI32_IH => MultibootTag::Efi32ImageHandle(read_u32(tag, 8)),
+            TAG_TYPE_EFI64_IH => MultibootTag::Efi64ImageHandle(read_u64(tag, 8)),
+            TAG_TYPE_LOAD_BASE_ADDR => MultibootTag::LoadBaseAddress(read_u32(tag, 8)),
+            _ => MultibootTag::Unknown {
+                tag_type,
+                data: &tag[8..],
+            },
+        })
+    }
+}
+
+impl MultibootTag {
+    /// タグの内容を1行で表示する(LOG_LEVEL_DEBUG)
+    pub fn print_info(&self) {
+        match self {
+            Self::CommandLine(c) => pr_debug!("Multiboot: Command line: {}", c),
+            Self::BootLoaderName(name) => pr_debug!("Multiboot: Boot loader: {}", name),
+            Self::Module(m) => pr_debug!(
+                "Multiboot: Module: {:#X} - {:#X} {}",
+                m.start_address,
+                m.end_address,
+                m.string
+            ),
+            Self::BasicMemoryInfo(m) => pr_debug!(
+                "Multiboot: Basic memory info: lower {}KiB, upper {}KiB",
+                m.memory_lower,
+                m.memory_upper
+            ),
+            Self::BiosBootDevice(d) => pr_debug!(
+                "Multiboot: BIOS boot device: {:#X} (partition: {:#X}, sub partition: {:#X})",
+                d.bios_device,
+                d.partition,
+                d.sub_partition
+            ),
+            Self::MemoryMap(m) => pr_debug!(
+                "Multiboot: Memory map: {} entries",
+                m.entries.len() / m.entry_size
+            ),
+            Self::FrameBuffer(f) => {
+                pr_debug!(
+                    "Multiboot: Frame buffer: {:#X} {}x{} {}bpp (pitch: {})",
+                    f.address,
+                    f.width,
+                    f.height,
+                    f.bpp,
+                    f.pitch
+                );
+                match f.color_info {
+                    FrameBufferColorInfo::Indexed {
+                        num_colors,
+                        palette,
+                    } => pr_debug!(
+                        "Multiboot:   Indexed color: {} colors ({} bytes)",
+                        num_colors,
+                        palette.len()
+                    ),
+                    FrameBufferColorInfo::Rgb {
+                        red_position,
+                        red_mask_size,
+                        green_position,
+                        green_mask_size,
+                        blue_position,
+                        blue_mask_size,
+                    } => pr_debug!(
+                        "Multiboot:   RGB color: R {}:{}, G {}:{}, B {}:{}",
+                        red_position,
+                        red_mask_size,
+                        green_position,
+                        green_mask_size,
+                        blue_position,
+                        blue_mask_size
+                    ),
+                    FrameBufferColorInfo::EgaText => pr_debug!("Multiboot:   EGA text"),
+                    FrameBufferColorInfo::Unknown(t) => {
+                        pr_debug!("Multiboot:   Unknown color type: {}", t)
+                    }
+                }
+            }
+            Self::ElfSections(e) => pr_debug!("Multiboot: ELF sections: {} sections", e.num),
+            Self::Apm(a) => pr_debug!(
+                "Multiboot: APM: version {:#X}, CS {:#X}:{:#X} (length: {:#X}), CS16 {:#X} (length: {:#X}), DS {:#X} (length: {:#X}), flags {:#X}",
+                a.version,
+                a.code_segment,
+                a.offset,
+                a.code_segment_length,
+                a.code_segment_16,
+                a.code_segment_16_length,
+                a.data_segment,
+                a.data_segment_length,
+                a.flags
+            ),
+            Self::Efi32SystemTable(address) => {
+                pr_debug!("Multiboot: EFI system table(32bit): {:#X}", address)
+            }
+            Self::Efi64SystemTable(address) => {
+                pr_debug!("Multiboot: EFI system table(64bit): {:#X}", address)
+            }
+            Self::Smbios(s) => pr_debug!(
+                "Multiboot: SMBIOS {}.{} ({} bytes)",
+                s.major,
+                s.minor,
+                s.tables.len()
+            ),
+            Self::AcpiOld(rsdp) => pr_debug!("Multiboot: RSDP(ACPI 1.0) ({} bytes)", rsdp.len()),
+            Self::AcpiNew(rsdp) => pr_debug!("Multiboot: RSDP(ACPI 2.0) ({} bytes)", rsdp.len()),
+            Self::Network(packet) => {
+                pr_debug!("Multiboot: DHCP ACK packet ({} bytes)", packet.len())
+            }
+            Self::EfiMemoryMap(m) => pr_debug!(
+                "Multiboot: EFI memory map: descriptor size {}, version {} ({} bytes)",
+                m.descriptor_size,
+                m.descriptor_version,
+                m.memory_map.len()
+            ),
+            Self::EfiBootServicesNotTerminated => {
+                pr_debug!("Multiboot: EFI boot services are not terminated")
+            }
+            Self::Efi32ImageHandle(handle) => {
+                pr_debug!("Multiboot: EFI image handle(32bit): {:#X}", handle)
+            }
+            Self::Efi64ImageHandle(handle) => {
+                pr_debug!("Multiboot: EFI image handle(64bit): {:#X}", handle)
+            }
+            Self::LoadBaseAddress(address) => {
+                pr_debug!("Multiboot: Load base address: {:#X}", address)
+            }
+            Self::Unknown { tag_type, data } => pr_debug!(
+                "Multiboot: Unknown tag (type: {}, {} bytes)",
+                tag_type,
+                data.len()
+            ),
+        }
+    }
+}
+
+impl MemoryMapTag {
+    pub fn entries(&self) -> MemoryMapEntryIter {
+        MemoryMapEntryIter {
+            entry_size: self.entry_size,
+            entries: self.entries,
+            pointer: 0,
+        }
+    }
+}
+
+impl Iterator for MemoryMapEntryIter {
+    type Item = MemoryMapEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pointer + self.entry_size > self.entries.len() {
+            return None;
+        }
+        let entry = MemoryMapEntry {
+            base_address: read_u64(self.entries, self.pointer),
+            length: read_u64(self.entries, self.pointer + 8),
+            memory_type: read_u32(self.entries, self.pointer + 16),
+        };
+        self.pointer += self.entry_size;
+        Some(entry)
+    }
+}
+
+impl MemoryMapEntry {
+    pub const TYPE_AVAILABLE: u32 = 1;
+    pub const TYPE_ACPI_RECLAIMABLE: u32 = 3;
+    pub const TYPE_ACPI_NVS: u32 = 4;
+    pub const TYPE_BAD_RAM: u32 = 5;
+
+    pub fn is_available(&self) -> bool {
+        self.memory_type == Self::TYPE_AVAILABLE
+    }
+}
+
+impl ElfSectionsTag {
+    pub fn sections(&self) -> ElfSectionIter {
+        ElfSectionIter {
+            tag: *self,
+            index: 0,
+        }
+    }
+}
+
+impl Iterator for ElfSectionIter {
+    type Item = ElfSection;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.tag.num {
+            return None;
+        }
+        let s = &self.tag.sections[(self.index * self.tag.entry_size)..];
+        self.index += 1;
+        Some(ElfSection {
+            flags: read_u64(s, 8),
+            address: read_u64(s, 16),
+            size: read_u64(s, 32),
+        })
+    }
+}
+
+impl ElfSection {
+    pub const FLAG_WRITE: u64 = 1;
+    pub const FLAG_ALLOC: u64 = 1 << 1;
+    pub const FLAG_EXECUTE: u64 = 1 << 2;
+
+    pub fn is_allocated(&self) -> bool {
+        self.flags & Self::FLAG_ALLOC != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag_type: u32, body: &[u8]) -> Vec<u8> {
+        let mut tag = Vec::new();
+        tag.extend_from_slice(&tag_type.to_le_bytes());
+        tag.extend_from_slice(&((TAG_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        tag.extend_from_slice(body);
+        while tag.len() & 7 != 0 {
+            tag.push(0);
+        }
+        tag
+    }
+
+    /// total_sizeとreservedの後にtagsと終端タグを並べる
+    fn build(tags: &[Vec<u8>], add_end_tag: bool) -> &'static [u8] {
+        let mut data = vec![0u8; 8];
+        for t in tags {
+            data.extend_from_slice(t);
+        }
+        if add_end_tag {
+            data.extend_from_slice(&tag(TAG_TYPE_END, &[]));
+        }
+        let total_size = data.len() as u32;
+        data[0..4].copy_from_slice(&total_size.to_le_bytes());
+        Box::leak(data.into_boxed_slice())
+    }
+
+    fn memory_map_tag(entries: &[(u64, u64, u32)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&24u32.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        for (base, length, memory_type) in entries {
+            body.extend_from_slice(&base.to_le_bytes());
+            body.extend_from_slice(&length.to_le_bytes());
+            body.extend_from_slice(&memory_type.to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+        }
+        tag(TAG_TYPE_MMAP, &body)
+    }
+
+    #[test]
+    fn parse_tags() {
+        let mut module = Vec::new();
+        module.extend_from_slice(&0x200000u32.to_le_bytes());
+        module.extend_from_slice(&0x201000u32.to_le_bytes());
+        module.extend_from_slice(b"font.pf2\0");
+        let data = build(
+            &[
+                tag(TAG_TYPE_CMDLINE, b"loglevel=7\0"),
+                tag(TAG_TYPE_MODULE, &module),
+                memory_map_tag(&[
+                    (0, 0x9f000, 1),
+                    (0x100000, 0x7ee0000, 1),
+                    (0xfffc0000, 0x40000, 2),
+                ]),
+                tag(99, &[1, 2, 3]),
+            ],
+            true,
+        );
+        let info = MultibootInformation::from_slice(0x1000, data).unwrap();
+        assert_eq!(info.get_address(), 0x1000);
+        assert_eq!(info.get_total_size(), data.len());
+
+        let mut tags = info.tags();
+        assert!(matches!(
+            tags.next(),
+            Some(MultibootTag::CommandLine("loglevel=7"))
+        ));
+        match tags.next() {
+            Some(MultibootTag::Module(m)) => {
+                assert_eq!(m.start_address, 0x200000);
+                assert_eq!(m.end_address, 0x201000);
+                assert_eq!(m.string, "font.pf2");
+            }
+            _ => panic!("module tag is expected"),
+        }
+        match tags.next() {
+            Some(MultibootTag::MemoryMap(m)) => {
+                let entries: Vec<_> = m.entries().collect();
+                assert_eq!(entries.len(), 3);
+                assert_eq!(entries[1].base_address, 0x100000);
+                assert_eq!(entries[1].length, 0x7ee0000);
+                assert!(entries[1].is_available());
+                assert!(!entries[2].is_available());
+            }
+            _ => panic!("memory map tag is expected"),
+        }
+        match tags.next() {
+            Some(MultibootTag::Unknown { tag_type, data }) => {
+                assert_eq!(tag_type, 99);
+                assert_eq!(data, &[1, 2, 3]);
+            }
+            _ => panic!("unknown tag is expected"),
+        }
+        assert!(tags.next().is_none());
+    }
+
+    #[test]
+    fn reject_tag_beyond_total_size() {
+        let mut data = build(&[tag(TAG_TYPE_CMDLINE, b"abc\0")], true).to_vec();
+        /* コマンドラインのタグのsizeを終端タグより後ろまで伸ばす */
+        data[12..16].copy_from_slice(&0x100u32.to_le_bytes());
+        let data = Box::leak(data.into_boxed_slice());
+        assert!(matches!(
+            MultibootInformation::from_slice(0, data),
+            Err(MultibootError::InvalidTagSize {
+                tag_type: TAG_TYPE_CMDLINE,
+                size: 0x100,
+                offset: 8
+            })
+        ));
+    }
+
+    #[test]
+    fn reject_too_small_tag() {
+        /* モジュールのタグは最低でも17byte必要 */
+        let data = build(&[tag(TAG_TYPE_MODULE, &[0; 4])], true);
+        assert!(matches!(
+            MultibootInformation::from_slice(0, data),
+            Err(MultibootError::InvalidTagSize {
+                tag_type: TAG_TYPE_MODULE,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reject_too_small_framebuffer_tag() {
+        /* framebuffer_typeの後ろの予約領域までで32byte必要 */
+        let data = build(&[tag(TAG_TYPE_FRAMEBUFFER, &[0; 23])], true);
+        assert!(matches!(
+            MultibootInformation::from_slice(0, data),
+            Err(MultibootError::InvalidTagSize {
+                tag_type: TAG_TYPE_FRAMEBUFFER,
+                size: 31,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn reject_invalid_memory_map_entry_size() {
+        let mut body = vec![0u8; 8 + 24];
+        body[0..4].copy_from_slice(&20u32.to_le_bytes());
+        let data = build(&[tag(TAG_TYPE_MMAP, &body)], true);
+        assert!(MultibootInformation::from_slice(0, data).is_err());
+    }
+
+    #[test]
+    fn reject_missing_end_tag() {
+        let data = build(&[tag(TAG_TYPE_CMDLINE, b"abc\0")], false);
+        assert!(matches!(
+            MultibootInformation::from_slice(0, data),
+            Err(MultibootError::EndTagNotFound)
+        ));
+    }
+
+    #[test]
+    fn reject_mismatched_total_size() {
+        let mut data = build(&[], true).to_vec();
+        data[0..4].copy_from_slice(&0x1000u32.to_le_bytes());
+        let data = Box::leak(data.into_boxed_slice());
+        assert!(matches!(
+            MultibootInformation::from_slice(0, data),
+            Err(MultibootError::InvalidTotalSize(_))
+        ));
+    }
+}
@@ -0,0 +1,690 @@
+//! ページング管理用モジュール
+//!
+//! boot.sで作成したPML4(`pml4`)を引き継ぎ、4KiB・2MiB・1GiBページの
+//! マップ・アンマップ・属性変更を行います。
+//! 新しいページテーブルはMemoryManagerから確保します(MemoryManagerの初期化前は静的な領域から確保します)。
+//!
+//! 物理メモリはDIRECT_MAP_START_ADDRESSからのダイレクトマップを通してアクセスします。
+//! boot.sで先頭4GiBをマップしており、それより上のメモリはmap_all_physical_memoryで、
+//! MMIOなどはmap_physical_memoryで必要に応じてマップします。
+//! 大きなページの一部だけを変更する場合は、そのページを小さなページに分割します。
+//! 置き換えたページテーブルは(boot.sの静的な領域の場合もあるため)解放しません。
+//! マップを書き換えた場合は、activateを呼び出した全てのCPUのTLBを無効化します。
+
+use super::local_apic::get_apic_id;
+use super::memory::PAGE_SIZE;
+use super::multiboot2::{ElfSection, ElfSectionsTag, MemoryMapEntry, MemoryMapTag};
+use super::spin_lock::SpinLock;
+use super::tlb::{shootdown, TlbContext, TlbFlushBatch};
+use super::MEMORY_MANAGER;
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const PAGE_SIZE_2M: usize = 0x200000;
+pub const PAGE_SIZE_1G: usize = 0x40000000;
+
+/// カーネルの仮想アドレスと物理アドレスの差(boot.s・ap_boot.sのKERNEL_VMA_OFFSETと同じ値)
+#[cfg(feature = "higher_half")]
+pub const KERNEL_VMA_OFFSET: usize = 0xffff_ffff_8000_0000;
+#[cfg(not(feature = "higher_half"))]
+pub const KERNEL_VMA_OFFSET: usize = 0;
+
+/// 物理アドレス0をマップしている仮想アドレス
+pub const DIRECT_MAP_START_ADDRESS: usize = 0xffff_8000_0000_0000;
+/// ダイレクトマップでマップできる物理アドレスの上限(64TiB)
+const MAX_DIRECT_MAP_ADDRESS: usize = 0x4000_0000_0000;
+/// boot.sでダイレクトマップにマップ済みの大きさ
+const BOOT_DIRECT_MAP_SIZE: usize = 0x1_0000_0000;
+
+/// ページテーブルを置けるアドレスの上限
+/// boot.sでマップ済みの範囲から確保しないと、ページテーブルを書き込む前にマップが必要になるため
+const MAX_PAGE_TABLE_ADDRESS: usize = BOOT_DIRECT_MAP_SIZE;
+
+/// MemoryManagerの初期化前(ACPIテーブルやフレームバッファのマップ)に使うページテーブルの数
+const NUM_OF_EARLY_PAGE_TABLES: usize = 16;
+
+const NUM_OF_ENTRIES: usize = 512;
+
+/// カーネルのアドレス空間に割り当てるPCID
+const KERNEL_PCID: u16 = 1;
+
+const PAGE_PRESENT: u64 = 1;
+const PAGE_WRITABLE: u64 = 1 << 1;
+const PAGE_USER: u64 = 1 << 2;
+const PAGE_WRITE_THROUGH: u64 = 1 << 3;
+const PAGE_CACHE_DISABLE: u64 = 1 << 4;
+const PAGE_SIZE_FLAG: u64 = 1 << 7;
+/// 4KiBページのPATビット
+const PAGE_PAT_4K: u64 = 1 << 7;
+/// 2MiB・1GiBページのPATビット
+const PAGE_PAT_HUGE: u64 = 1 << 12;
+const PAGE_EXECUTE_DISABLE: u64 = 1 << 63;
+const PAGE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum CacheMode {
+    WriteBack,
+    UncachedMinus,
+    Uncacheable,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct PageAttribute {
+    pub writable: bool,
+    pub execute_disable: bool,
+    pub user: bool,
+    pub cache_mode: CacheMode,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+#[derive(Debug)]
+pub enum PagingError {
+    InvalidAddress,
+    MemoryAllocationFailed,
+    NotMapped,
+}
+
+pub struct PageManager {
+    /// PML4の物理アドレス
+    pml4: SpinLock<usize>,
+    is_1g_page_supported: bool,
+    tlb_context: TlbContext,
+    /// このアドレス空間を使っているCPUのAPIC ID
+    active_cpus: SpinLock<Vec<u32>>,
+}
+
+#[repr(C, align(4096))]
+struct EarlyPageTables([[u64; NUM_OF_ENTRIES]; NUM_OF_EARLY_PAGE_TABLES]);
+
+static mut EARLY_PAGE_TABLES: EarlyPageTables =
+    EarlyPageTables([[0; NUM_OF_ENTRIES]; NUM_OF_EARLY_PAGE_TABLES]);
+static NUM_OF_USED_EARLY_PAGE_TABLES: AtomicUsize = AtomicUsize::new(0);
+
+enum EntrySearchResult {
+    /// エントリとそのページの大きさ
+    Found(&'static mut u64, usize),
+    /// マップされていなかった。次に調べるべきアドレスを返す。
+    NotFound(usize),
+}
+
+impl PageAttribute {
+    /// カーネルのデータ(読み書き可能・実行不可)
+    pub const KERNEL_DATA: Self = Self::new(true, true, false, CacheMode::WriteBack);
+    /// カーネルの読み込み専用データ
+    pub const KERNEL_READ_ONLY: Self = Self::new(false, true, false, CacheMode::WriteBack);
+    /// メモリマップドI/O
+    pub const MMIO: Self = Self::new(true, true, false, CacheMode::Uncacheable);
+
+    pub const fn new(
+        writable: bool,
+        execute_disable: bool,
+        user: bool,
+        cache_mode: CacheMode,
+    ) -> Self {
+        Self {
+            writable,
+            execute_disable,
+            user,
+            cache_mode,
+        }
+    }
+
+    fn to_entry_flags(self, is_huge_page: bool) -> u64 {
+        let mut flags = PAGE_PRESENT;
+        if self.writable {
+            flags |= PAGE_WRITABLE;
+        }
+        if self.user {
+            flags |= PAGE_USER;
+        }
+        if self.execute_disable {
+            flags |= PAGE_EXECUTE_DISABLE;
+        }
+        /* PATは既定値(WB, WT, UC-, UC)のまま使う(WTは使っていない) */
+        flags |= match self.cache_mode {
+            CacheMode::WriteBack => 0,
+            CacheMode::UncachedMinus => PAGE_CACHE_DISABLE,
+            CacheMode::Uncacheable => PAGE_CACHE_DISABLE | PAGE_WRITE_THROUGH,
+        };
+        if is_huge_page {
+            flags |= PAGE_SIZE_FLAG;
+        }
+        flags
+    }
+}
+
+impl PageSize {
+    pub const fn to_usize(self) -> usize {
+        match self {
+            PageSize::Size4K => PAGE_SIZE,
+            PageSize::Size2M => PAGE_SIZE_2M,
+            PageSize::Size1G => PAGE_SIZE_1G,
+        }
+    }
+
+    /// このページを指すエントリがあるページテーブルの段数(PT = 1, PD = 2, PDPT = 3)
+    const fn get_level(self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 3,
+        }
+    }
+}
+
+/// 物理アドレスをダイレクトマップ上の仮想アドレスに変換する
+pub const fn phys_to_virt(physical_address: usize) -> usize {
+    physical_address + DIRECT_MAP_START_ADDRESS
+}
+
+/// ダイレクトマップ上の仮想アドレスを物理アドレスに変換する
+pub const fn virt_to_phys(virtual_address: usize) -> usize {
+    virtual_address - DIRECT_MAP_START_ADDRESS
+}
+
+/// カーネル本体(静的変数など)の仮想アドレスを物理アドレスに変換する
+pub const fn kernel_virt_to_phys(virtual_address: usize) -> usize {
+    virtual_address - KERNEL_VMA_OFFSET
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+const fn align_down(value: usize, align: usize) -> usize {
+    value & !(align - 1)
+}
+
+const fn get_shift(level: usize) -> usize {
+    12 + 9 * (level - 1)
+}
+
+fn get_index(address: usize, level: usize) -> usize {
+    (address >> get_shift(level)) & (NUM_OF_ENTRIES - 1)
+}
+
+fn get_table(address: usize) -> &'static mut [u64; NUM_OF_ENTRIES] {
+    unsafe { &mut *(phys_to_virt(address) as *mut [u64; NUM_OF_ENTRIES]) }
+}
+
+/// CPUID(EAX=0x80000001)のEDXを返す
+fn get_extended_cpu_features() -> u32 {
+    let edx: u32;
+    unsafe {
+        asm!(
+            "push rbx",
+            "cpuid",
+            "pop rbx",
+            inout("eax") 0x80000001u32 => _,
+            inout("ecx") 0u32 => _,
+            lateout("edx") edx
+        )
+    };
+    edx
+}
+
+/// 0埋めされた新しいページテーブルを確保する
+fn alloc_page_table() -> Result<usize, PagingError> {
+    let index = NUM_OF_USED_EARLY_PAGE_TABLES.fetch_add(1, Ordering::Relaxed);
+    let address = if index < NUM_OF_EARLY_PAGE_TABLES {
+        kernel_virt_to_phys(unsafe { &EARLY_PAGE_TABLES.0[index] as *const _ as usize })
+    } else {
+        NUM_OF_USED_EARLY_PAGE_TABLES.store(NUM_OF_EARLY_PAGE_TABLES, Ordering::Relaxed);
+        unsafe {
+            MEMORY_MANAGER.alloc_with_align_below(PAGE_SIZE, PAGE_SIZE, MAX_PAGE_TABLE_ADDRESS)
+        }
+        .ok_or(PagingError::MemoryAllocationFailed)?
+    };
+    get_table(address).fill(0);
+    Ok(address)
+}
+
+/// levelの段にある大きなページのエントリを一段小さいページを並べたページテーブルに置き換える
+fn split_huge_page(entry: &mut u64, level: usize) -> Result<(), PagingError> {
+    let table_address = alloc_page_table()?;
+    let table = get_table(table_address);
+    let base_address = *entry & PAGE_ADDRESS_MASK & !(PAGE_PAT_HUGE);
+    let mut flags = *entry & !PAGE_ADDRESS_MASK;
+    if level == 2 {
+        /* 4KiBページではPSビットの位置がPATビットになる */
+        let pat = (*entry & PAGE_PAT_HUGE) != 0;
+        flags &= !PAGE_SIZE_FLAG;
+        if pat {
+            flags |= PAGE_PAT_4K;
+        }
+    } else {
+        flags |= *entry & PAGE_PAT_HUGE;
+    }
+    let child_page_size = 1u64 << get_shift(level - 1);
+    for (i, e) in table.iter_mut().enumerate() {
+        *e = (base_address + i as u64 * child_page_size) | flags;
+    }
+    *entry = table_address as u64 | PAGE_PRESENT | PAGE_WRITABLE | (*entry & PAGE_USER);
+    Ok(())
+}
+
+impl PageManager {
+    pub const fn const_new() -> Self {
+        Self {
+            pml4: SpinLock::new(0),
+            is_1g_page_supported: false,
+            tlb_context: TlbContext::const_new(),
+            active_cpus: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// boot.sで作成したPML4を使用する
+    pub fn new() -> Self {
+        extern "C" {
+            static pml4: u8;
+        }
+        Self {
+            pml4: SpinLock::new(kernel_virt_to_phys(unsafe { &pml4 as *const u8 as usize })),
+            is_1g_page_supported: (get_extended_cpu_features() & (1 << 26)) != 0,
+            tlb_context: TlbContext::new(KERNEL_PCID),
+            active_cpus: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// このCPUでこのアドレス空間を使い始める(CR3を読み込み直す)
+    ///
+    /// 以降はマップを書き換えた際にこのCPUのTLBも無効化します。
+    /// 各CPUでinit_cpu_callを呼び出した後に呼び出してください。
+    pub fn activate(&self) {
+        let apic_id = get_apic_id();
+        /* 読み込んだ後に書き換えられても無効化されるよう、先に登録する */
+        {
+            let mut active_cpus = self.active_cpus.lock();
+            if !active_cpus.contains(&apic_id) {
+                active_cpus.push(apic_id);
+            }
+        }
+        let cr3 = self.tlb_context.prepare_cr3(*self.pml4.lock());
+        unsafe { asm!("mov cr3, {}", in(reg) cr3, options(nostack)) };
+    }
+
+    /// batchのTLBをこのCPUとこのアドレス空間を使っている全てのCPUで無効化する
+    fn flush_tlb(&self, batch: &TlbFlushBatch) {
+        let active_cpus = self.active_cpus.lock().clone();
+        shootdown(batch, &self.tlb_context, &active_cpus);
+    }
+
+    /// 物理アドレスphysical_addressからのsize分をvirtual_addressへマップする
+    ///
+    /// 既にマップされている場合は置き換えます。
+    /// アドレスとサイズの配置が許す限り大きなページを使います。
+    pub fn map(
+        &self,
+        virtual_address: usize,
+        physical_address: usize,
+        size: usize,
+        attribute: PageAttribute,
+    ) -> Result<(), PagingError> {
+        if ((virtual_address | physical_address | size) & (PAGE_SIZE - 1)) != 0 {
+            return Err(PagingError::InvalidAddress);
+        }
+        let mut batch = TlbFlushBatch::new();
+        let result = self.map_range(
+            virtual_address,
+            physical_address,
+            size,
+            attribute,
+            &mut batch,
+        );
+        self.flush_tlb(&batch);
+        result
+    }
+
+    fn map_range(
+        &self,
+        virtual_address: usize,
+        physical_address: usize,
+        size: usize,
+        attribute: PageAttribute,
+        batch: &mut TlbFlushBatch,
+    ) -> Result<(), PagingError> {
+        let pml4 = self.pml4.lock();
+        let mut mapped_size = 0;
+        while mapped_size < size {
+            let v = virtual_address + mapped_size;
+            let p = physical_address + mapped_size;
+            let remaining = size - mapped_size;
+            let page_size = if self.is_1g_page_supported
+                && ((v | p) & (PAGE_SIZE_1G - 1)) == 0
+                && remaining >= PAGE_SIZE_1G
+            {
+                PageSize::Size1G
+            } else if ((v | p) & (PAGE_SIZE_2M - 1)) == 0 && remaining >= PAGE_SIZE_2M {
+                PageSize::Size2M
+            } else {
+                PageSize::Size4K
+            };
+            Self::map_page(*pml4, v, p, page_size, attribute, batch)?;
+            mapped_size += page_size.to_usize();
+        }
+        Ok(())
+    }
+
+    fn map_page(
+        pml4: usize,
+        virtual_address: usize,
+        physical_address: usize,
+        page_size: PageSize,
+        attribute: PageAttribute,
+        batch: &mut TlbFlushBatch,
+    ) -> Result<(), PagingError> {
+        let target_level = page_size.get_level();
+        let mut table = pml4;
+        for level in (target_level..=4).rev() {
+            let entry = &mut get_table(table)[get_index(virtual_address, level)];
+            if level == target_level {
+                /* マップされていなかったページはTLBに載っていない */
+                if (*entry & PAGE_PRESENT) != 0 {
+                    batch.add_page(virtual_address, page_size.to_usize());
+                }
+                *entry = physical_address as u64 | attribute.to_entry_flags(level != 1);
+                return Ok(());
+            }
+            if (*entry & PAGE_PRESENT) == 0 {
+                *entry = alloc_page_table()? as u64 | PAGE_PRESENT | PAGE_WRITABLE;
+            } else if level <= 3 && (*entry & PAGE_SIZE_FLAG) != 0 {
+                split_huge_page(entry, level)?;
+            }
+            if attribute.user {
+                *entry |= PAGE_USER;
+            }
+            table = (*entry & PAGE_ADDRESS_MASK) as usize;
+        }
+        unreachable!()
+    }
+
+    /// virtual_addressを含むページのエントリを探す
+    ///
+    /// 見つかったページがvirtual_addressから始まっていないか、endを超える場合は分割します。
+    fn find_entry(
+        pml4: usize,
+        virtual_address: usize,
+        end: usize,
+    ) -> Result<EntrySearchResult, PagingError> {
+        let mut table = pml4;
+        for level in (1..=4).rev() {
+            let entry = &mut get_table(table)[get_index(virtual_address, level)];
+            let page_size = 1usize << get_shift(level);
+            if (*entry & PAGE_PRESENT) == 0 {
+                return Ok(EntrySearchResult::NotFound(
+                    (virtual_address & !(page_size - 1)) + page_size,
+                ));
+            }
+            if level == 1 || (level <= 3 && (*entry & PAGE_SIZE_FLAG) != 0) {
+                if level != 1
+                    && ((virtual_address & (page_size - 1)) != 0
+                        || virtual_address + page_size > end)
+                {
+                    split_huge_page(entry, level)?;
+                } else {
+                    return Ok(EntrySearchResult::Found(entry, page_size));
+                }
+            }
+            table = (*entry & PAGE_ADDRESS_MASK) as usize;
+        }
+        unreachable!()
+    }
+
+    /// virtual_addressからのsize分のマップを解除する(マップされていない部分は無視します)
+    pub fn unmap(&self, virtual_address: usize, size: usize) -> Result<(), PagingError> {
+        if ((virtual_address | size) & (PAGE_SIZE - 1)) != 0 {
+            return Err(PagingError::InvalidAddress);
+        }
+        let mut batch = TlbFlushBatch::new();
+        let result = self.unmap_range(virtual_address, size, &mut batch);
+        self.flush_tlb(&batch);
+        result
+    }
+
+    fn unmap_range(
+        &self,
+        virtual_address: usize,
+        size: usize,
+        batch: &mut TlbFlushBatch,
+    ) -> Result<(), PagingError> {
+        let pml4 = self.pml4.lock();
+        let end = virtual_address + size;
+        let mut address = virtual_address;
+        while address < end {
+            match Self::find_entry(*pml4, address, end)? {
+                EntrySearchResult::Found(entry, page_size) => {
+                    *entry = 0;
+                    batch.add_page(address, page_size);
+                    address += page_size;
+                }
+                EntrySearchResult::NotFound(next) => address = next,
+            }
+        }
+        Ok(())
+    }
+
+    /// virtual_addressからのsize分の属性を変更する(物理アドレスは変更しません)
+    pub fn change_attribute(
+        &self,
+        virtual_address: usize,
+        size: usize,
+        attribute: PageAttribute,
+    ) -> Result<(), PagingError> {
+        if ((virtual_address | size) & (PAGE_SIZE - 1)) != 0 {
+            return Err(PagingError::InvalidAddress);
+        }
+        let mut batch = TlbFlushBatch::new();
+        let result = self.change_attribute_range(virtual_address, size, attribute, &mut batch);
+        self.flush_tlb(&batch);
+        result
+    }
+
+    fn change_attribute_range(
+        &self,
+        virtual_address: usize,
+        size: usize,
+        attribute: PageAttribute,
+        batch: &mut TlbFlushBatch,
+    ) -> Result<(), PagingError> {
+        let pml4 = self.pml4.lock();
+        let end = virtual_address + size;
+        let mut address = virtual_address;
+        while address < end {
+            match Self::find_entry(*pml4, address, end)? {
+                EntrySearchResult::Found(entry, page_size) => {
+                    let physical_address = *entry & PAGE_ADDRESS_MASK;
+                    *entry = physical_address | attribute.to_entry_flags(page_size != PAGE_SIZE);
+                    batch.add_page(address, page_size);
+                    address += page_size;
+                }
+                EntrySearchResult::NotFound(_) => return Err(PagingError::NotMapped),
+            }
+        }
+        Ok(())
+    }
+
+    /// 仮想アドレスを物理アドレスに変換する
+    pub fn get_physical_address(&self, virtual_address: usize) -> Option<usize> {
+        let pml4 = self.pml4.lock();
+        let mut table = *pml4;
+        for level in (1..=4).rev() {
+            let entry = get_table(table)[get_index(virtual_address, level)];
+            if (entry & PAGE_PRESENT) == 0 {
+                return None;
+            }
+            if level == 1 || (level <= 3 && (entry & PAGE_SIZE_FLAG) != 0) {
+                let page_size = 1usize << get_shift(level);
+                let mut base_address = entry & PAGE_ADDRESS_MASK;
+                if level != 1 {
+                    base_address &= !PAGE_PAT_HUGE;
+                }
+                return Some(base_address as usize | (virtual_address & (page_size - 1)));
+            }
+            table = (entry & PAGE_ADDRESS_MASK) as usize;
+        }
+        None
+    }
+
+    /// physical_addressからのsize分をダイレクトマップ上に指定した属性でマップし、仮想アドレスを返す
+    ///
+    /// MMIOのように、先頭4GiBより上にあったり、キャッシュ属性を変える必要のある領域に使います。
+    pub fn map_physical_memory(
+        &self,
+        physical_address: usize,
+        size: usize,
+        attribute: PageAttribute,
+    ) -> Result<usize, PagingError> {
+        if size == 0 || physical_address + size > MAX_DIRECT_MAP_ADDRESS {
+            return Err(PagingError::InvalidAddress);
+        }
+        let start = align_down(physical_address, PAGE_SIZE);
+        let end = align_up(physical_address + size, PAGE_SIZE);
+        self.map(phys_to_virt(start), start, end - start, attribute)?;
+        Ok(phys_to_virt(physical_address))
+    }
+
+    /// MMIO領域をキャッシュ無効でダイレクトマップ上にマップし、仮想アドレスを返す
+    pub fn map_mmio(&self, physical_address: usize, size: usize) -> Result<usize, PagingError> {
+        self.map_physical_memory(physical_address, size, PageAttribute::MMIO)
+    }
+
+    /// physical_addressからのsize分のうち、ダイレクトマップ上でマップされていない部分をマップし、
+    /// 仮想アドレスを返す
+    ///
+    /// 既にマップされている部分の属性は変更しません。
+    pub fn ensure_direct_map(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> Result<usize, PagingError> {
+        if size == 0 || physical_address + size > MAX_DIRECT_MAP_ADDRESS {
+            return Err(PagingError::InvalidAddress);
+        }
+        let end = align_up(physical_address + size, PAGE_SIZE);
+        let mut address = align_down(physical_address, PAGE_SIZE);
+        while address < end {
+            if self.get_physical_address(phys_to_virt(address)).is_some() {
+                address += PAGE_SIZE;
+                continue;
+            }
+            let mut unmapped_end = address + PAGE_SIZE;
+            while unmapped_end < end
+                && self
+                    .get_physical_address(phys_to_virt(unmapped_end))
+                    .is_none()
+            {
+                unmapped_end += PAGE_SIZE;
+            }
+            self.map(
+                phys_to_virt(address),
+                address,
+                unmapped_end - address,
+                PageAttribute::KERNEL_DATA,
+            )?;
+            address = unmapped_end;
+        }
+        Ok(phys_to_virt(physical_address))
+    }
+
+    /// メモリマップ上のRAMのうち、boot.sでマップしていない部分をダイレクトマップにマップする
+    pub fn map_all_physical_memory(&self, map: &MemoryMapTag) -> Result<(), PagingError> {
+        for e in map.entries() {
+            if !matches!(
+                e.memory_type,
+                MemoryMapEntry::TYPE_AVAILABLE
+                    | MemoryMapEntry::TYPE_ACPI_RECLAIMABLE
+                    | MemoryMapEntry::TYPE_ACPI_NVS
+            ) {
+                continue;
+            }
+            let start = align_down(e.base_address as usize, PAGE_SIZE).max(BOOT_DIRECT_MAP_SIZE);
+            let end = align_up((e.base_address + e.length) as usize, PAGE_SIZE)
+                .min(MAX_DIRECT_MAP_ADDRESS);
+            if start < end {
+                self.map(
+                    phys_to_virt(start),
+                    start,
+                    end - start,
+                    PageAttribute::KERNEL_DATA,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// ELFセクションのフラグに合わせてカーネルの各ページの属性を変更する
+    ///
+    /// 書き込み可能でないセクションは読み込み専用に、実行可能でないセクションは実行不可にします。
+    /// 1つのページに複数のセクションが含まれる場合は、いずれかのセクションで許可されている操作を許可します。
+    pub fn protect_kernel_sections(
+        &self,
+        elf_sections: &ElfSectionsTag,
+    ) -> Result<(), PagingError> {
+        let sections = || {
+            elf_sections
+                .sections()
+                .filter(|s| s.is_allocated() && s.size != 0)
+                .map(|s| (s.address as usize, (s.address + s.size) as usize, s.flags))
+        };
+        let get_page_attribute = |page: usize| {
+            let mut attribute: Option<PageAttribute> = None;
+            for (start, end, flags) in sections() {
+                if start < page + PAGE_SIZE && page < end {
+                    let a = attribute.get_or_insert(PageAttribute::KERNEL_READ_ONLY);
+                    if (flags & ElfSection::FLAG_WRITE) != 0 {
+                        a.writable = true;
+                    }
+                    if (flags & ElfSection::FLAG_EXECUTE) != 0 {
+                        a.execute_disable = false;
+                    }
+                }
+            }
+            attribute
+        };
+
+        let kernel_start = align_down(
+            sections().map(|(start, _, _)| start).min().unwrap_or(0),
+            PAGE_SIZE,
+        );
+        let kernel_end = align_up(
+            sections().map(|(_, end, _)| end).max().unwrap_or(0),
+            PAGE_SIZE,
+        );
+        /* 同じ属性が続く範囲ごとにまとめて変更する */
+        let mut area: Option<(usize, PageAttribute)> = None;
+        for page in (kernel_start..kernel_end).step_by(PAGE_SIZE) {
+            let attribute = get_page_attribute(page);
+            if let Some((area_start, area_attribute)) = area {
+                if attribute == Some(area_attribute) {
+                    continue;
+                }
+                self.change_attribute(area_start, page - area_start, area_attribute)?;
+            }
+            area = attribute.map(|a| (page, a));
+        }
+        if let Some((area_start, area_attribute)) = area {
+            self.change_attribute(area_start, kernel_end - area_start, area_attribute)?;
+        }
+        Ok(())
+    }
+
+    /// boot.sで作成した仮想アドレス = 物理アドレスのマップ(PML4の先頭のエントリ)を解除する
+    ///
+    /// APの起動用コードはこのマップ上で動くため、全てのAPを起動した後に呼んでください。
+    /// activateを呼び出した全てのCPUのTLBを無効化します。
+    #[cfg(feature = "higher_half")]
+    pub fn remove_identity_map(&self) {
+        get_table(*self.pml4.lock())[0] = 0;
+        let mut batch = TlbFlushBatch::new();
+        batch.set_full_flush();
+        self.flush_tlb(&batch);
+    }
+}
@@ -0,0 +1,175 @@
+//! 並列処理
+//!
+//! 範囲を小さな区間に分け、init_cpu_callを呼び出した全てのCPU(呼び出したCPUを含む)で分担して処理します。
+//! 各CPUは処理の終わった順に次の区間を取るので、CPUごとに処理の速さが違っても偏りにくくなります。
+
+use super::cpu_call::{get_num_of_online_cpus, run_on_all_cpus};
+use super::spin_lock::SpinLock;
+
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// 1つのCPUあたりの区間の数の目安
+const NUM_OF_CHUNKS_PER_CPU: usize = 4;
+
+/// 範囲を区間に分けて配る
+struct ChunkDistributor {
+    range: Range<usize>,
+    chunk_size: usize,
+    next: AtomicUsize,
+}
+
+impl ChunkDistributor {
+    fn new(range: Range<usize>) -> Self {
+        Self::with_num_of_chunks(
+            range,
+            get_num_of_online_cpus().max(1) * NUM_OF_CHUNKS_PER_CPU,
+        )
+    }
+
+    fn with_num_of_chunks(range: Range<usize>, num_of_chunks: usize) -> Self {
+        let chunk_size = range.len().div_ceil(num_of_chunks).max(1);
+        Self {
+            next: AtomicUsize::new(range.start),
+            range,
+            chunk_size,
+        }
+    }
+
+    fn next_chunk(&self) -> Option<Range<usize>> {
+        let end = self.range.end;
+        let get_chunk_end = |start: usize| start.saturating_add(self.chunk_size).min(end);
+        /* rangeの終わりで止め、usize::MAX付近でも桁あふれしないようにする */
+        let start = self
+            .next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |start| {
+                if start >= end {
+                    None
+                } else {
+                    Some(get_chunk_end(start))
+                }
+            })
+            .ok()?;
+        Some(start..get_chunk_end(start))
+    }
+}
+
+/// rangeを区間に分けて全てのCPUでfuncを実行し、全ての区間が終わるまで待つ
+///
+/// funcには重ならない区間が渡されます(どの区間がどのCPUで処理されるかは決まっていません)。
+pub fn parallel_for<F: Fn(Range<usize>) + Send + Sync>(range: Range<usize>, func: F) {
+    if range.is_empty() {
+        return;
+    }
+    let distributor = ChunkDistributor::new(range);
+    /* 実行を始めずにタイムアウトしたCPUがあっても、残りのCPU(少なくともこのCPU)が全ての区間を処理する */
+    let _ = run_on_all_cpus(|| {
+        while let Some(chunk) = distributor.next_chunk() {
+            func(chunk);
+        }
+    });
+}
+
+/// rangeを区間に分けて全てのCPUでfuncを実行し、その結果をjoinでまとめて返す
+///
+/// 結果をまとめる順番は決まっていないので、joinは結合法則と交換法則を満たす必要があります。
+/// rangeが空の場合はNoneを返します。
+pub fn parallel_reduce<T, F, J>(range: Range<usize>, func: F, join: J) -> Option<T>
+where
+    T: Send,
+    F: Fn(Range<usize>) -> T + Send + Sync,
+    J: Fn(T, T) -> T + Send + Sync,
+{
+    if range.is_empty() {
+        return None;
+    }
+    let distributor = ChunkDistributor::new(range);
+    let result: SpinLock<Option<T>> = SpinLock::new(None);
+    let join_option = |a: Option<T>, b: T| match a {
+        Some(a) => join(a, b),
+        None => b,
+    };
+    /* 実行を始めずにタイムアウトしたCPUがあっても、残りのCPU(少なくともこのCPU)が全ての区間を処理する */
+    let _ = run_on_all_cpus(|| {
+        /* CPUの中でまとめてから、全体の結果にまとめる */
+        let mut local_result = None;
+        while let Some(chunk) = distributor.next_chunk() {
+            local_result = Some(join_option(local_result, func(chunk)));
+        }
+        if let Some(local_result) = local_result {
+            let mut result = result.lock();
+            *result = Some(join_option(result.take(), local_result));
+        }
+    });
+    let result = result.lock().take();
+    result
+}
+
+/// parallel_forで埋めたバッファの合計をparallel_reduceで求め、正しいかを確かめる(起動時の確認用)
+///
+/// 全てのCPUがinit_cpu_callを呼び出した後に呼び出してください。
+pub fn check_parallel() -> bool {
+    const NUM_OF_ELEMENTS: usize = 0x10000;
+    let buffer: Vec<AtomicUsize> = (0..NUM_OF_ELEMENTS).map(|_| AtomicUsize::new(0)).collect();
+    parallel_for(0..NUM_OF_ELEMENTS, |chunk| {
+        for i in chunk {
+            buffer[i].fetch_add(i, Ordering::Relaxed);
+        }
+    });
+    let sum = parallel_reduce(
+        0..NUM_OF_ELEMENTS,
+        |chunk| {
+            chunk
+                .map(|i| buffer[i].load(Ordering::Relaxed))
+                .sum::<usize>()
+        },
+        |a, b| a + b,
+    );
+    sum == Some(NUM_OF_ELEMENTS * (NUM_OF_ELEMENTS - 1) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect_chunks(distributor: &ChunkDistributor) -> Vec<Range<usize>> {
+        let mut chunks = Vec::new();
+        while let Some(chunk) = distributor.next_chunk() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn chunks_cover_range() {
+        let distributor = ChunkDistributor::with_num_of_chunks(3..103, 8);
+        assert_eq!(distributor.chunk_size, 13);
+        let chunks = collect_chunks(&distributor);
+        assert_eq!(chunks.len(), 8);
+        assert_eq!(chunks.first(), Some(&(3..16)));
+        assert_eq!(chunks.last(), Some(&(94..103)));
+        assert!(chunks.windows(2).all(|c| c[0].end == c[1].start));
+        assert_eq!(distributor.next_chunk(), None);
+    }
+
+    #[test]
+    fn range_smaller_than_num_of_chunks() {
+        let distributor = ChunkDistributor::with_num_of_chunks(0..3, 16);
+        assert_eq!(collect_chunks(&distributor), [0..1, 1..2, 2..3]);
+    }
+
+    #[test]
+    fn range_near_usize_max() {
+        let range = (usize::MAX - 10)..usize::MAX;
+        let distributor = ChunkDistributor::with_num_of_chunks(range.clone(), 3);
+        let chunks = collect_chunks(&distributor);
+        assert_eq!(chunks.first().map(|c| c.start), Some(range.start));
+        assert_eq!(chunks.last().map(|c| c.end), Some(range.end));
+        assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), range.len());
+        /* 終わった後に何度呼び出しても先頭に戻らない */
+        for _ in 0..4 {
+            assert_eq!(distributor.next_chunk(), None);
+        }
+    }
+}
@@ -5,6 +5,8 @@
 mod graphic;
 mod serial_port;
 
+use super::boot_option::ConsoleSetting;
+
 use graphic::GraphicManager;
 use serial_port::SerialPortManager;
 
@@ -12,9 +14,20 @@ use core::fmt;
 
 pub static mut PRINT_MANAGER: PrintManager = PrintManager::new();
 
+/* ログレベル(小さいほど重要) */
+pub const LOG_LEVEL_ERROR: u8 = 3;
+pub const LOG_LEVEL_WARNING: u8 = 4;
+pub const LOG_LEVEL_INFO: u8 = 6;
+pub const LOG_LEVEL_DEBUG: u8 = 7;
+/// 既定ではLOG_LEVEL_DEBUG未満のメッセージを表示する
+pub const LOG_LEVEL_DEFAULT: u8 = LOG_LEVEL_DEBUG;
+
 pub struct PrintManager {
     serial_port_manager: SerialPortManager,
     graphic_manager: GraphicManager,
+    enable_serial_port: bool,
+    enable_graphic: bool,
+    log_level: u8,
 }
 
 impl PrintManager {
@@ -23,9 +36,25 @@ impl PrintManager {
             /* COM1: QEMUなどのシリアルポートタブで表示されるポート用 */
             serial_port_manager: SerialPortManager::new(0x3F8),
             graphic_manager: GraphicManager::new(),
+            enable_serial_port: true,
+            enable_graphic: true,
+            log_level: LOG_LEVEL_DEFAULT,
         }
     }
 
+    pub fn set_console(&mut self, console: &ConsoleSetting) {
+        self.enable_serial_port = console.serial_port;
+        self.enable_graphic = console.graphic;
+    }
+
+    pub fn set_log_level(&mut self, log_level: u8) {
+        self.log_level = log_level;
+    }
+
+    pub fn is_printable_level(&self, level: u8) -> bool {
+        level < self.log_level
+    }
+
     pub fn init(
         &mut self,
         frame_buffer_address: usize,
@@ -48,12 +77,17 @@ impl PrintManager {
 
 impl fmt::Write for PrintManager {
     fn write_str(&mut self, string: &str) -> fmt::Result {
-        self.serial_port_manager.send_str(string);
-        self.graphic_manager.draw_string(string);
+        if self.enable_serial_port {
+            self.serial_port_manager.send_str(string);
+        }
+        if self.enable_graphic {
+            self.graphic_manager.draw_string(string);
+        }
         Ok(())
     }
 }
 
+#[cfg(not(test))]
 pub fn print(args: fmt::Arguments) {
     use core::fmt::Write;
     unsafe {
@@ -61,6 +95,18 @@ pub fn print(args: fmt::Arguments) {
     }
 }
 
+/// ホストでのテストではI/Oポートに触れず、標準出力に表示する
+#[cfg(test)]
+pub fn print(args: fmt::Arguments) {
+    std::print!("{}", args);
+}
+
+pub fn print_with_level(level: u8, args: fmt::Arguments) {
+    if unsafe { PRINT_MANAGER.is_printable_level(level) } {
+        print(args);
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
@@ -73,3 +119,27 @@ macro_rules! println {
     ($fmt:expr) => (print!(concat!($fmt,"\n")));
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"),$($arg)*)); //\nをつける
 }
+
+#[macro_export]
+macro_rules! pr_err {
+    ($fmt:expr) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_ERROR, format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_ERROR, format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
+
+#[macro_export]
+macro_rules! pr_warn {
+    ($fmt:expr) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_WARNING, format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_WARNING, format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
+
+#[macro_export]
+macro_rules! pr_info {
+    ($fmt:expr) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_INFO, format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_INFO, format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
+
+#[macro_export]
+macro_rules! pr_debug {
+    ($fmt:expr) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_DEBUG, format_args!(concat!($fmt, "\n"))));
+    ($fmt:expr, $($arg:tt)*) => ($crate::print::print_with_level($crate::print::LOG_LEVEL_DEBUG, format_args!(concat!($fmt, "\n"), $($arg)*)));
+}
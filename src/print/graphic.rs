@@ -96,7 +96,7 @@ impl GraphicManager {
         let mut font_char_index_size = 0;
 
         while pointer < font_size {
-            use core::{str, u16, u32};
+            use core::str;
 
             let section_type =
                 str::from_utf8(unsafe { &*((font_address + pointer) as *const [u8; 4]) })
@@ -272,7 +272,7 @@ impl GraphicManager {
         if self.frame_buffer_address == 0 {
             return false;
         }
-        for c in s.chars().into_iter() {
+        for c in s.chars() {
             if c == '\n' {
                 self.cursor_x = 0;
                 self.cursor_y += self.max_font_height as usize;
@@ -304,6 +304,6 @@ impl GraphicManager {
                 self.cursor_x += font_data.device_width as usize;
             }
         }
-        return true;
+        true
     }
 }
@@ -32,8 +32,8 @@ impl SerialPortManager {
 
     pub fn send_str(&self, s: &str) {
         for c in s.bytes() {
-            if c as char == '\n' {
-                self.send('\r' as u8);
+            if c == b'\n' {
+                self.send(b'\r');
             }
             self.send(c);
         }
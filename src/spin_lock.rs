@@ -0,0 +1,99 @@
+//! スピンロック
+//!
+//! 複数のコアから同時に触られるデータを保護するためのロックです。
+//! ロックを保持している間は割り込みを禁止し、割り込みハンドラ内で同じロックを取ろうとして
+//! デッドロックすることを防ぎます。
+
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub struct SpinLock<T> {
+    lock_flag: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    interrupt_flag: bool,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+/// 割り込みを禁止し、禁止する前に割り込みが有効だったかを返す
+pub fn save_and_disable_interrupt() -> bool {
+    let rflags: u64;
+    unsafe { asm!("pushfq", "pop {}", "cli", out(reg) rflags) };
+    (rflags & (1 << 9)) != 0
+}
+
+pub fn restore_interrupt(interrupt_flag: bool) {
+    if interrupt_flag {
+        unsafe { asm!("sti") };
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            lock_flag: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupt_flag = save_and_disable_interrupt();
+        while self
+            .lock_flag
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.lock_flag.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        SpinLockGuard {
+            lock: self,
+            interrupt_flag,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupt_flag = save_and_disable_interrupt();
+        if self
+            .lock_flag
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(SpinLockGuard {
+                lock: self,
+                interrupt_flag,
+            })
+        } else {
+            restore_interrupt(interrupt_flag);
+            None
+        }
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.lock_flag.store(false, Ordering::Release);
+        restore_interrupt(self.interrupt_flag);
+    }
+}
@@ -0,0 +1,163 @@
+//! カーネルスタック管理用モジュール
+//!
+//! 各CPUのスタックを専用の仮想アドレス領域に確保し、その直下にマップしないページ(ガードページ)を置きます。
+//! スタックがあふれるとガードページへのアクセスで例外が発生するので、
+//! 例外ハンドラはfind_overflowed_stackでどのCPUのスタックがあふれたかを調べられます。
+
+use super::memory::{align_up, PAGE_SIZE};
+use super::paging::PageAttribute;
+use super::spin_lock::SpinLock;
+use super::{MEMORY_MANAGER, PAGE_MANAGER, STACK_MANAGER};
+
+use core::arch::asm;
+
+pub const DEFAULT_STACK_SIZE: usize = 0x8000;
+/// スタック用の仮想アドレス領域(ダイレクトマップの直後から512GiB)
+const STACK_AREA_START_ADDRESS: usize = 0xffff_c000_0000_0000;
+const STACK_AREA_SIZE: usize = 0x80_0000_0000;
+const GUARD_PAGE_SIZE: usize = PAGE_SIZE;
+
+#[derive(Clone, Copy)]
+pub struct KernelStack {
+    /// BSPを0とした、CPUの起動順の番号
+    pub cpu_number: usize,
+    /// ガードページの先頭アドレス
+    pub guard_page_address: usize,
+    /// スタックの底(最も大きいアドレス)
+    pub stack_top: usize,
+}
+
+pub struct StackManager {
+    stack_list: SpinLock<StackList>,
+}
+
+struct StackList {
+    stacks: [Option<KernelStack>; StackManager::MAX_NUM_OF_STACKS],
+    num_of_stacks: usize,
+    next_address: usize,
+}
+
+impl KernelStack {
+    pub fn get_size(&self) -> usize {
+        self.stack_top - (self.guard_page_address + GUARD_PAGE_SIZE)
+    }
+
+    pub fn is_guard_page(&self, address: usize) -> bool {
+        self.guard_page_address <= address && address < self.guard_page_address + GUARD_PAGE_SIZE
+    }
+}
+
+impl StackManager {
+    /// 各CPUがカーネルスタックとIST用のスタックを確保するので、CPUの数の4倍程度必要です。
+    pub const MAX_NUM_OF_STACKS: usize = 2048;
+    /// find_overflowed_stackがロックの取得を試す回数
+    const MAX_LOCK_RETRIES: usize = 0x100000;
+
+    pub const fn const_new() -> Self {
+        Self {
+            stack_list: SpinLock::new(StackList {
+                stacks: [None; Self::MAX_NUM_OF_STACKS],
+                num_of_stacks: 0,
+                next_address: STACK_AREA_START_ADDRESS,
+            }),
+        }
+    }
+
+    /// cpu_number番目のCPU用にガードページ付きのスタックを確保する
+    ///
+    /// sizeはページ単位に切り上げます。
+    pub fn alloc_stack(&self, cpu_number: usize, size: usize) -> Option<KernelStack> {
+        let size = align_up(size.max(1), PAGE_SIZE);
+        /* マップ中は(TLBの無効化でIPIを待つこともあるため)ロックを持たないよう、先に場所だけ予約する */
+        let (index, guard_page_address) = {
+            let mut stack_list = self.stack_list.lock();
+            if stack_list.num_of_stacks >= Self::MAX_NUM_OF_STACKS
+                || stack_list.next_address + GUARD_PAGE_SIZE + size
+                    > STACK_AREA_START_ADDRESS + STACK_AREA_SIZE
+            {
+                return None;
+            }
+            let index = stack_list.num_of_stacks;
+            let guard_page_address = stack_list.next_address;
+            stack_list.num_of_stacks += 1;
+            stack_list.next_address = guard_page_address + GUARD_PAGE_SIZE + size;
+            (index, guard_page_address)
+        };
+        let stack_address = guard_page_address + GUARD_PAGE_SIZE;
+
+        /* 失敗した場合、予約した仮想アドレスは再利用しない(stacks[index]はNoneのまま) */
+        let physical_address = unsafe { MEMORY_MANAGER.alloc(size) }?;
+        /* ガードページはマップしない */
+        if unsafe {
+            PAGE_MANAGER.map(
+                stack_address,
+                physical_address,
+                size,
+                PageAttribute::KERNEL_DATA,
+            )
+        }
+        .is_err()
+        {
+            /* 途中までマップしたページを解除してから物理メモリを返す */
+            if unsafe { PAGE_MANAGER.unmap(stack_address, size) }.is_ok() {
+                unsafe { MEMORY_MANAGER.free(physical_address, size) };
+            }
+            return None;
+        }
+
+        let stack = KernelStack {
+            cpu_number,
+            guard_page_address,
+            stack_top: stack_address + size,
+        };
+        self.stack_list.lock().stacks[index] = Some(stack);
+        Some(stack)
+    }
+
+    /// addressがいずれかのスタックのガードページ内であれば、そのスタックを返す
+    ///
+    /// 例外ハンドラから呼ばれるため、デッドロックしないようにロックの取得は一定回数だけ試し、
+    /// 取得できなかった場合はNoneを返します。
+    /// ロックを持つのは一覧を更新する間だけなので、他のCPUが持っていてもすぐに解放されます。
+    pub fn find_overflowed_stack(&self, address: usize) -> Option<KernelStack> {
+        let stack_list = (0..Self::MAX_LOCK_RETRIES).find_map(|_| {
+            let stack_list = self.stack_list.try_lock();
+            if stack_list.is_none() {
+                core::hint::spin_loop();
+            }
+            stack_list
+        })?;
+        stack_list.stacks[..stack_list.num_of_stacks]
+            .iter()
+            .filter_map(|s| *s)
+            .find(|s| s.is_guard_page(address))
+    }
+}
+
+/// fault_addressがガードページ内であれば、スタックオーバーフローとして停止する
+///
+/// ページフォルトなどの例外ハンドラから呼び出します。
+pub fn check_stack_overflow(fault_address: usize) {
+    if let Some(stack) = unsafe { STACK_MANAGER.find_overflowed_stack(fault_address) } {
+        panic!(
+            "Stack overflow on CPU {} (Address: {:#X}, Stack: {:#X} - {:#X})",
+            stack.cpu_number,
+            fault_address,
+            stack.stack_top - stack.get_size(),
+            stack.stack_top
+        );
+    }
+}
+
+/// スタックをstack_topに切り替えてfuncを呼び出す
+pub fn switch_stack(stack_top: usize, func: extern "C" fn() -> !) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {0}",
+            "call {1}",
+            in(reg) stack_top,
+            in(reg) func,
+            options(noreturn)
+        )
+    }
+}
@@ -0,0 +1,330 @@
+//! TLBの無効化(TLB shootdown)
+//!
+//! ページテーブルを書き換えたページをTlbFlushBatchにまとめ、そのアドレス空間を使っている全てのCPUで
+//! 無効化します。他のCPUにはcpu_callのIPIで依頼し、全てのCPUが終わるまで待ちます。
+//! ページが多すぎる場合はinvlpgを繰り返す代わりにアドレス空間全体を無効化します。
+//! PCIDが使える場合はアドレス空間にPCIDを割り当て、全体の無効化はINVPCIDでそのPCIDのみに行います。
+//! 起動時にはcheck_tlb_shootdownで、ページの付け替えが全てのCPUに反映されるかを確かめます。
+
+use super::cpu_call::{run_on_all_cpus, run_on_cpu_async, CallHandle};
+use super::local_apic::get_apic_id;
+use super::memory::PAGE_SIZE;
+use super::paging::{phys_to_virt, PageAttribute};
+use super::tsc::cpuid;
+use super::{MEMORY_MANAGER, PAGE_MANAGER};
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// TlbFlushBatchにまとめておける範囲の数
+const MAX_NUM_OF_RANGES: usize = 16;
+/// これより多くのページを無効化する場合はアドレス空間全体を無効化する
+const FULL_FLUSH_THRESHOLD: usize = 64;
+
+const CR4_PCIDE: u64 = 1 << 17;
+const INVPCID_SINGLE_CONTEXT: u64 = 1;
+
+/// check_tlb_shootdownで使う仮想アドレス(カーネルスタック用の領域の直後で、他では使っていない)
+const TEST_PAGE_ADDRESS: usize = 0xffff_c080_0000_0000;
+
+/// アドレス空間のTLBの扱い方(PCIDを使うかどうか)
+#[derive(Clone, Copy)]
+pub struct TlbContext {
+    /// PCIDが使えない場合はNone
+    pcid: Option<u16>,
+    is_invpcid_supported: bool,
+}
+
+/// 無効化するページの一覧
+#[derive(Clone, Copy)]
+pub struct TlbFlushBatch {
+    /// (先頭の仮想アドレス, ページの数, ページの大きさ)
+    ranges: [(usize, usize, usize); MAX_NUM_OF_RANGES],
+    num_of_ranges: usize,
+    num_of_pages: usize,
+    is_full_flush: bool,
+}
+
+fn read_cr4() -> u64 {
+    let cr4: u64;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack)) };
+    cr4
+}
+
+fn write_cr4(cr4: u64) {
+    unsafe { asm!("mov cr4, {}", in(reg) cr4, options(nostack)) };
+}
+
+fn invlpg(virtual_address: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) virtual_address, options(nostack)) };
+}
+
+impl TlbContext {
+    pub const fn const_new() -> Self {
+        Self {
+            pcid: None,
+            is_invpcid_supported: false,
+        }
+    }
+
+    /// CPUがPCIDに対応していればpcidを使う
+    pub fn new(pcid: u16) -> Self {
+        let max_leaf = cpuid(0, 0).0;
+        let is_pcid_supported = (cpuid(1, 0).2 & (1 << 17)) != 0;
+        Self {
+            pcid: if is_pcid_supported { Some(pcid) } else { None },
+            is_invpcid_supported: is_pcid_supported
+                && max_leaf >= 7
+                && (cpuid(7, 0).1 & (1 << 10)) != 0,
+        }
+    }
+
+    /// pml4を指すCR3の値を返し、PCIDを使う場合はこのCPUのPCIDを有効にする
+    ///
+    /// 返した値を書き込む際、63bit目を立てていないので以前のそのPCIDのTLBは無効化されます。
+    /// このため、しばらく使っていなかったアドレス空間に切り替えても古いTLBは残りません。
+    pub fn prepare_cr3(&self, pml4: usize) -> u64 {
+        if let Some(pcid) = self.pcid {
+            let cr4 = read_cr4();
+            if (cr4 & CR4_PCIDE) == 0 {
+                /* PCIDEを立てる時はCR3の下位12bit(現在のPCID)が0である必要がある */
+                unsafe {
+                    asm!(
+                        "mov {0}, cr3",
+                        "and {0}, {1}",
+                        "mov cr3, {0}",
+                        out(reg) _,
+                        in(reg) !0xfffu64,
+                        options(nostack)
+                    )
+                };
+                write_cr4(cr4 | CR4_PCIDE);
+            }
+            pml4 as u64 | pcid as u64
+        } else {
+            pml4 as u64
+        }
+    }
+
+    /// このCPUのこのアドレス空間のTLBを全て無効化する
+    fn flush_all(&self) {
+        if let Some(pcid) = self.pcid.filter(|_| self.is_invpcid_supported) {
+            let descriptor: [u64; 2] = [pcid as u64, 0];
+            unsafe {
+                asm!(
+                    "invpcid {0}, [{1}]",
+                    in(reg) INVPCID_SINGLE_CONTEXT,
+                    in(reg) &descriptor,
+                    options(readonly, nostack)
+                )
+            };
+        } else {
+            /* PCIDEが立っていてもCR3の63bit目が0なので現在のPCIDのTLBは無効化される */
+            unsafe { asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack)) };
+        }
+    }
+}
+
+impl TlbFlushBatch {
+    pub const fn new() -> Self {
+        Self {
+            ranges: [(0, 0, 0); MAX_NUM_OF_RANGES],
+            num_of_ranges: 0,
+            num_of_pages: 0,
+            is_full_flush: false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.is_full_flush && self.num_of_pages == 0
+    }
+
+    /// virtual_addressからのpage_sizeの大きさのページを追加する
+    ///
+    /// 前に追加したページと連続していれば同じ範囲にまとめます。
+    pub fn add_page(&mut self, virtual_address: usize, page_size: usize) {
+        if self.is_full_flush {
+            return;
+        }
+        self.num_of_pages += 1;
+        if self.num_of_pages > FULL_FLUSH_THRESHOLD {
+            self.is_full_flush = true;
+            return;
+        }
+        if let Some((start, num_of_pages, size)) = self.ranges[..self.num_of_ranges].last_mut() {
+            if *size == page_size && *start + *num_of_pages * *size == virtual_address {
+                *num_of_pages += 1;
+                return;
+            }
+        }
+        if self.num_of_ranges == MAX_NUM_OF_RANGES {
+            self.is_full_flush = true;
+            return;
+        }
+        self.ranges[self.num_of_ranges] = (virtual_address, 1, page_size);
+        self.num_of_ranges += 1;
+    }
+
+    /// アドレス空間全体を無効化するようにする
+    #[cfg(feature = "higher_half")]
+    pub fn set_full_flush(&mut self) {
+        self.is_full_flush = true;
+    }
+
+    /// このCPUのTLBを無効化する
+    pub fn flush_local(&self, context: &TlbContext) {
+        if self.is_full_flush {
+            context.flush_all();
+            return;
+        }
+        for (start, num_of_pages, page_size) in self.ranges[..self.num_of_ranges].iter() {
+            for i in 0..*num_of_pages {
+                invlpg(start + i * page_size);
+            }
+        }
+    }
+}
+
+/// このCPUとcpusのCPUでbatchのTLBを無効化し、全てのCPUが終わるまで待つ
+///
+/// 他のCPUがロックを取ろうとして割り込み禁止のまま待っているとIPIを処理できないため、
+/// ページテーブルのロックを解放してから呼び出してください。
+pub fn shootdown(batch: &TlbFlushBatch, context: &TlbContext, cpus: &[u32]) {
+    if batch.is_empty() {
+        return;
+    }
+    if cpus.is_empty() {
+        /* Local APICの初期化前はAPIC IDを読めない */
+        batch.flush_local(context);
+        return;
+    }
+    let apic_id = get_apic_id();
+    let handles: Vec<CallHandle> = cpus
+        .iter()
+        .filter(|id| **id != apic_id)
+        .filter_map(|id| {
+            let (batch, context) = (*batch, *context);
+            match run_on_cpu_async(*id, move || batch.flush_local(&context)) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    pr_warn!("Cannot flush TLB on CPU(APIC ID: {}): {:?}", id, e);
+                    None
+                }
+            }
+        })
+        .collect();
+    batch.flush_local(context);
+    for handle in handles {
+        if let Err(e) = handle.wait() {
+            pr_warn!("Cannot flush TLB on some CPUs: {:?}", e);
+        }
+    }
+}
+
+/// 全てのCPUで読んだページを別のフレームに付け替え、全てのCPUで新しい内容が読めるかを確かめる
+///
+/// 起動時の確認用です。全てのCPUがinit_cpu_callとPageManager::activateを呼び出した後に呼び出してください。
+pub fn check_tlb_shootdown() -> bool {
+    let frame = if let Some(frame) = unsafe { MEMORY_MANAGER.alloc(PAGE_SIZE * 2) } {
+        frame
+    } else {
+        return false;
+    };
+    let frames = [frame, frame + PAGE_SIZE];
+    for (i, frame) in frames.iter().enumerate() {
+        unsafe { *(phys_to_virt(*frame) as *mut usize) = i };
+    }
+    let num_of_mismatches = AtomicUsize::new(0);
+    let read_test_page = |expected: usize| {
+        let result = run_on_all_cpus(|| {
+            if unsafe { core::ptr::read_volatile(TEST_PAGE_ADDRESS as *const usize) } != expected {
+                num_of_mismatches.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        if result.is_err() {
+            num_of_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+
+    /* 全てのCPUのTLBに古いフレームを載せてから付け替える */
+    let mut is_mapped = true;
+    for (i, frame) in frames.iter().enumerate() {
+        if unsafe {
+            PAGE_MANAGER.map(
+                TEST_PAGE_ADDRESS,
+                *frame,
+                PAGE_SIZE,
+                PageAttribute::KERNEL_DATA,
+            )
+        }
+        .is_err()
+        {
+            is_mapped = false;
+            break;
+        }
+        read_test_page(i);
+    }
+
+    if unsafe { PAGE_MANAGER.unmap(TEST_PAGE_ADDRESS, PAGE_SIZE) }.is_ok() {
+        unsafe { MEMORY_MANAGER.free(frame, PAGE_SIZE * 2) };
+    }
+    is_mapped && num_of_mismatches.load(Ordering::Relaxed) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE_2M: usize = 0x200000;
+
+    #[test]
+    fn merge_contiguous_pages() {
+        let mut batch = TlbFlushBatch::new();
+        assert!(batch.is_empty());
+        for i in 0..4 {
+            batch.add_page(0x1000 + i * PAGE_SIZE, PAGE_SIZE);
+        }
+        batch.add_page(0x200000, PAGE_SIZE_2M);
+        batch.add_page(0x400000, PAGE_SIZE_2M);
+        /* ページの大きさが違う場合はまとめない */
+        batch.add_page(0x600000, PAGE_SIZE);
+        assert!(!batch.is_empty());
+        assert!(!batch.is_full_flush);
+        assert_eq!(
+            batch.ranges[..batch.num_of_ranges],
+            [
+                (0x1000, 4, PAGE_SIZE),
+                (0x200000, 2, PAGE_SIZE_2M),
+                (0x600000, 1, PAGE_SIZE)
+            ]
+        );
+    }
+
+    #[test]
+    fn full_flush_over_threshold() {
+        let mut batch = TlbFlushBatch::new();
+        for i in 0..FULL_FLUSH_THRESHOLD {
+            batch.add_page(i * PAGE_SIZE, PAGE_SIZE);
+        }
+        assert!(!batch.is_full_flush);
+        batch.add_page(FULL_FLUSH_THRESHOLD * PAGE_SIZE, PAGE_SIZE);
+        assert!(batch.is_full_flush);
+        assert!(!batch.is_empty());
+    }
+
+    #[test]
+    fn full_flush_when_ranges_are_full() {
+        let mut batch = TlbFlushBatch::new();
+        for i in 0..MAX_NUM_OF_RANGES {
+            batch.add_page(i * 2 * PAGE_SIZE, PAGE_SIZE);
+        }
+        assert!(!batch.is_full_flush);
+        assert_eq!(batch.num_of_ranges, MAX_NUM_OF_RANGES);
+        /* 最後の範囲に続くページはまとめられる */
+        batch.add_page((MAX_NUM_OF_RANGES * 2 - 1) * PAGE_SIZE, PAGE_SIZE);
+        assert!(!batch.is_full_flush);
+        batch.add_page(MAX_NUM_OF_RANGES * 4 * PAGE_SIZE, PAGE_SIZE);
+        assert!(batch.is_full_flush);
+    }
+}
@@ -0,0 +1,266 @@
+//! TSC(Time Stamp Counter)
+//!
+//! CPUごとにクロックに合わせて増えていく64bitのカウンタです。
+//! Invariant TSCに対応しているCPUでは周波数の変化や省電力状態に関係なく一定の速さで増えるので、
+//! Local APIC TimerのTSC-Deadlineモードの期限や時間の測定、ナノ秒単位の単調増加する時計として使います。
+//! 周波数はCPUIDの0x15番から求め、得られない場合はACPI PM Timerと比べて測定します。
+//! それもできない場合はCPUIDの0x16番の基本周波数で代用します。
+//! 各CPUのTSCがそろっているかは、APの起動時にBSPと交互に読み比べて確かめます。
+
+use super::acpi_pm_timer::AcpiPmTimer;
+use super::spin_lock::SpinLock;
+use super::TSC;
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// PM Timerで周波数を測定する時間
+const CALIBRATION_TIME_MS: usize = 50;
+/// 同期の確認で各CPUがTSCを読む回数
+const SYNC_CHECK_ITERATIONS: usize = 10000;
+/// 同期の確認で相手のCPUを待つ時間
+const SYNC_TIMEOUT_MS: u64 = 1000;
+/// 周波数が分からないときに待ち時間の上限を決めるために仮定する最大の周波数
+const MAX_FREQUENCY_HZ: u64 = 10000000000;
+const NANOSECONDS_PER_SECOND: u128 = 1000000000;
+
+pub struct Tsc {
+    frequency_hz: u64,
+    is_invariant: bool,
+    is_deadline_supported: bool,
+    /// get_monotonic_nsで0とするTSCの値
+    base_count: u64,
+    /// これまでに確かめたCPUのTSCがすべてBSPとそろっていたか
+    is_synchronized: AtomicBool,
+}
+
+/* 同期の確認用(APは一つずつ起動するので一組だけ用意する) */
+static SYNC_ARRIVED_CPUS: AtomicUsize = AtomicUsize::new(0);
+static SYNC_FINISHED_CPUS: AtomicUsize = AtomicUsize::new(0);
+static SYNC_LAST_COUNT: SpinLock<u64> = SpinLock::new(0);
+static SYNC_MAX_WARP: AtomicU64 = AtomicU64::new(0);
+
+impl Tsc {
+    pub const fn const_new() -> Self {
+        Self {
+            frequency_hz: 0,
+            is_invariant: false,
+            is_deadline_supported: false,
+            base_count: 0,
+            is_synchronized: AtomicBool::new(true),
+        }
+    }
+
+    /// TSCの機能を調べ、周波数を求める
+    pub fn new(pm_timer: &AcpiPmTimer) -> Self {
+        let max_leaf = cpuid(0, 0).0;
+        let max_extended_leaf = cpuid(0x80000000, 0).0;
+        let is_invariant =
+            max_extended_leaf >= 0x80000007 && (cpuid(0x80000007, 0).3 & (1 << 8)) != 0;
+        let is_deadline_supported = (cpuid(1, 0).2 & (1 << 24)) != 0;
+        let frequency_hz = get_frequency_from_cpuid(max_leaf)
+            .or_else(|| measure_frequency(pm_timer))
+            .or_else(|| get_base_frequency_from_cpuid(max_leaf))
+            .unwrap_or_else(|| {
+                pr_warn!("Cannot get the frequency of TSC");
+                0
+            });
+        Self {
+            frequency_hz,
+            is_invariant,
+            is_deadline_supported,
+            base_count: read_tsc(),
+            is_synchronized: AtomicBool::new(true),
+        }
+    }
+
+    pub fn get_frequency(&self) -> u64 {
+        self.frequency_hz
+    }
+
+    pub fn is_invariant(&self) -> bool {
+        self.is_invariant
+    }
+
+    pub fn is_deadline_supported(&self) -> bool {
+        self.is_deadline_supported
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.is_synchronized.load(Ordering::Relaxed)
+    }
+
+    /// nsをTSCのカウント数に変換する
+    pub fn ns_to_count(&self, ns: u64) -> u64 {
+        (ns as u128 * self.frequency_hz as u128 / NANOSECONDS_PER_SECOND) as u64
+    }
+
+    /// Tsc::newを呼び出してからの経過時間(ns)
+    ///
+    /// is_synchronizedがfalseの場合、CPUをまたいで比べた値は単調増加になりません。
+    pub fn get_monotonic_ns(&self) -> u64 {
+        if self.frequency_hz == 0 {
+            return 0;
+        }
+        (read_tsc().saturating_sub(self.base_count) as u128 * NANOSECONDS_PER_SECOND
+            / self.frequency_hz as u128) as u64
+    }
+
+    /// msの間に増えるTSCのカウント数(待ち時間の上限に使う)
+    ///
+    /// 周波数が分からない場合はMAX_FREQUENCY_HZとして長めに見積もります。
+    pub fn get_timeout_count(&self, ms: u64) -> u64 {
+        if self.frequency_hz == 0 {
+            MAX_FREQUENCY_HZ / 1000 * ms
+        } else {
+            self.ns_to_count(ms * 1000000)
+        }
+    }
+
+    /// ns後のTSCの値(TSC-Deadlineモードの期限に使う)
+    pub fn get_deadline_after_ns(&self, ns: u64) -> u64 {
+        read_tsc().saturating_add(self.ns_to_count(ns))
+    }
+
+    /// 起動したAPがcheck_tsc_sync_with_bspを呼び出すのに合わせてBSPで呼び出し、TSCのずれを確かめる
+    ///
+    /// 見つかったずれの最大値(TSCのカウント数)を返します。
+    /// APがSYNC_TIMEOUT_MS以内に確認を終えなかった場合はNoneを返します。
+    /// どちらの場合もずれがあるとしてis_synchronizedがfalseになります。
+    pub fn check_sync_with_ap(&self) -> Option<u64> {
+        let timeout_count = self.get_timeout_count(SYNC_TIMEOUT_MS);
+        let is_finished = check_tsc_warp(timeout_count)
+            && wait_for_sync_cpus(
+                &SYNC_FINISHED_CPUS,
+                read_tsc().saturating_add(timeout_count),
+            );
+        let warp = SYNC_MAX_WARP.swap(0, Ordering::Relaxed);
+        *SYNC_LAST_COUNT.lock() = 0;
+        SYNC_FINISHED_CPUS.store(0, Ordering::Relaxed);
+        SYNC_ARRIVED_CPUS.store(0, Ordering::Release);
+        if !is_finished || warp != 0 {
+            self.is_synchronized.store(false, Ordering::Relaxed);
+        }
+        if is_finished {
+            Some(warp)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tsc::check_sync_with_apの相手としてAPで呼び出す
+pub fn check_tsc_sync_with_bsp() {
+    if !check_tsc_warp(unsafe { TSC.get_timeout_count(SYNC_TIMEOUT_MS) }) {
+        pr_warn!("BSP did not join the TSC synchronization check");
+    }
+}
+
+/// 2つのCPUで交互にTSCを読み、相手が直前に読んだ値より小さい値を読んだらずれとして記録する
+///
+/// 相手のCPUがtimeout_count(TSCのカウント数)の間に来なかった場合はfalseを返します。
+fn check_tsc_warp(timeout_count: u64) -> bool {
+    let timeout = read_tsc().saturating_add(timeout_count);
+    SYNC_ARRIVED_CPUS.fetch_add(1, Ordering::AcqRel);
+    if !wait_for_sync_cpus(&SYNC_ARRIVED_CPUS, timeout) {
+        return false;
+    }
+    for _ in 0..SYNC_CHECK_ITERATIONS {
+        let mut last_count = SYNC_LAST_COUNT.lock();
+        let count = read_tsc();
+        if *last_count > count {
+            SYNC_MAX_WARP.fetch_max(*last_count - count, Ordering::Relaxed);
+        }
+        *last_count = count;
+    }
+    SYNC_FINISHED_CPUS.fetch_add(1, Ordering::Release);
+    true
+}
+
+/// counterが2になるまで、TSCがtimeoutに達するまで待つ
+fn wait_for_sync_cpus(counter: &AtomicUsize, timeout: u64) -> bool {
+    while counter.load(Ordering::Acquire) < 2 {
+        if read_tsc() >= timeout {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+/// TSCを読む(前の命令が終わるまで待つ)
+pub fn read_tsc() -> u64 {
+    let eax: u32;
+    let edx: u32;
+    unsafe { asm!("lfence", "rdtsc", out("eax") eax, out("edx") edx, options(nomem, nostack)) };
+    ((edx as u64) << 32) | eax as u64
+}
+
+/// CPUIDの0x15番(TSCとクリスタルの周波数の比)から周波数を求める
+fn get_frequency_from_cpuid(max_leaf: u32) -> Option<u64> {
+    if max_leaf < 0x15 {
+        return None;
+    }
+    let (denominator, numerator, crystal_hz, _) = cpuid(0x15, 0);
+    if denominator == 0 || numerator == 0 || crystal_hz == 0 {
+        return None;
+    }
+    Some(crystal_hz as u64 * numerator as u64 / denominator as u64)
+}
+
+/// CPUIDの0x16番(プロセッサの基本周波数)を周波数とみなす
+///
+/// 多くのCPUではTSCは基本周波数で増えますが、正確な値ではありません。
+fn get_base_frequency_from_cpuid(max_leaf: u32) -> Option<u64> {
+    if max_leaf < 0x16 {
+        return None;
+    }
+    match cpuid(0x16, 0).0 & 0xffff {
+        0 => None,
+        mhz => Some(mhz as u64 * 1000000),
+    }
+}
+
+/// PM TimerでCALIBRATION_TIME_MSの間にTSCがいくつ増えるかを測定する
+///
+/// PM Timerが進まない場合に備え、TSCがMAX_FREQUENCY_HZで増えるとしてCALIBRATION_TIME_MSの2倍が過ぎたら諦めます。
+fn measure_frequency(pm_timer: &AcpiPmTimer) -> Option<u64> {
+    let calibration_count = AcpiPmTimer::FREQUENCY_HZ * CALIBRATION_TIME_MS / 1000;
+    let timeout_count = MAX_FREQUENCY_HZ / 1000 * CALIBRATION_TIME_MS as u64 * 2;
+    let start = pm_timer.get_count();
+    let start_tsc = read_tsc();
+    let elapsed = loop {
+        let elapsed = pm_timer.get_elapsed_count(start);
+        if elapsed >= calibration_count {
+            break elapsed;
+        } else if read_tsc() - start_tsc >= timeout_count {
+            pr_warn!("ACPI PM Timer does not count up");
+            return None;
+        }
+        core::hint::spin_loop();
+    };
+    let end_tsc = read_tsc();
+    Some((end_tsc - start_tsc) * AcpiPmTimer::FREQUENCY_HZ as u64 / elapsed as u64)
+        .filter(|f| *f != 0)
+}
+
+/// (EAX, EBX, ECX, EDX)を返す
+pub fn cpuid(leaf: u32, sub_leaf: u32) -> (u32, u32, u32, u32) {
+    let eax: u32;
+    let ebx: u64;
+    let ecx: u32;
+    let edx: u32;
+    unsafe {
+        asm!(
+            "mov {0}, rbx",
+            "cpuid",
+            "xchg {0}, rbx",
+            out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") sub_leaf => ecx,
+            lateout("edx") edx,
+            options(nomem, nostack)
+        )
+    };
+    (eax, ebx as u32, ecx, edx)
+}
@@ -0,0 +1,81 @@
+//! TSS(Task State Segment)管理用モジュール
+//!
+//! 64bitモードのTSSはタスク切り替えには使わず、特権レベル変更時のスタックと
+//! IST(Interrupt Stack Table)のスタックのアドレスを保持するために使います。
+//! #DF・NMI・#MCは壊れたスタックの上で発生することがあるので、専用のスタックに切り替えて処理します。
+//! BSPはboot.sのTSSを使い、APはcreate_tssで作成したTSSを使います。
+//! どちらもPerCpuDataに保存し、get_per_cpu_data().get_tss()で参照します。
+
+use super::STACK_MANAGER;
+
+use alloc::boxed::Box;
+
+pub const IST_DOUBLE_FAULT: u8 = 1;
+pub const IST_NMI: u8 = 2;
+pub const IST_MACHINE_CHECK: u8 = 3;
+/// IST用のスタック1つあたりの大きさ
+pub const IST_STACK_SIZE: usize = 0x4000;
+/// I/O許可ビットマップで扱うポートの数(boot.sのIO_MAP_SIZEと同じ)
+const IO_MAP_SIZE: usize = 0xffff;
+
+#[repr(C, packed)]
+pub struct TaskStateSegment {
+    reserved0: u32,
+    /// 特権レベル0~2に移るときのスタック
+    pub rsp: [u64; 3],
+    reserved1: u64,
+    /// IST1~7のスタック
+    pub ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    pub io_map_base: u16,
+}
+
+/// I/O許可ビットマップ付きのTSS(boot.sのTSSと同じ並び)
+#[repr(C, packed)]
+pub struct TaskStateSegmentWithIoMap {
+    pub tss: TaskStateSegment,
+    /// 1のビットのポートは特権レベル3からアクセスできない(最後の1byteは終端)
+    pub io_map: [u8; IO_MAP_SIZE / 8 + 1],
+}
+
+/// boot.sで作成し、BSPが読み込んでいるTSS
+///
+/// 可変参照が複数できないよう、BSPのPerCpuDataを作成するときに一度だけ呼び出してください。
+pub unsafe fn get_boot_tss() -> &'static mut TaskStateSegmentWithIoMap {
+    extern "C" {
+        static mut tss: TaskStateSegmentWithIoMap;
+    }
+    &mut tss
+}
+
+/// cpu_number番目のCPU用に、ISTを設定したTSSを作成する
+///
+/// I/O許可ビットマップはboot.sのTSSと同じく全ポートを禁止にします。
+pub fn create_tss(cpu_number: usize) -> &'static mut TaskStateSegmentWithIoMap {
+    let tss = Box::leak(Box::new(TaskStateSegmentWithIoMap {
+        tss: TaskStateSegment {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            io_map_base: core::mem::size_of::<TaskStateSegment>() as u16,
+        },
+        io_map: [0xff; IO_MAP_SIZE / 8 + 1],
+    }));
+    set_ist_stacks(&mut tss.tss, cpu_number);
+    tss
+}
+
+/// #DF・NMI・#MC用のスタックを確保してtssのISTに設定する
+pub fn set_ist_stacks(tss: &mut TaskStateSegment, cpu_number: usize) {
+    let mut ist = tss.ist;
+    for index in [IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK] {
+        let stack = unsafe { STACK_MANAGER.alloc_stack(cpu_number, IST_STACK_SIZE) }
+            .expect("Cannot allocate the stack for IST");
+        ist[index as usize - 1] = stack.stack_top as u64;
+    }
+    tss.ist = ist;
+}